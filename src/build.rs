@@ -1,72 +1,219 @@
 use std::{
-    env,
-    fs::{self},
-    os::{self, unix::prelude::PermissionsExt},
+    collections::BTreeMap,
+    env, fs,
     path::{Path, PathBuf},
     sync::{Arc, Mutex, MutexGuard},
 };
 
 use anyhow::{anyhow, Result};
+use ratatui::{
+    style::{Color, Style},
+    text::Line,
+};
 
 use crate::{
-    choices::{EnumInput, TextInput, TextInputType},
+    choices::{EnumInput, ReviewInput, TextInput, TextInputType},
     download::download,
-    process::{run_process, run_process_user},
+    kconfig, log,
+    pipeline::Stage,
+    process::{capture_process, run_process, run_process_attached, run_process_user, shell_quote},
+    profile::{self, Profile},
+    progress::{self, Progress},
+    state,
     ui::UI,
 };
 
+// State file with earlier answers to the prompts
+const HISTORY: &str = "history";
+const HISTORY_LEN: usize = 20;
+
 enum Source {
     Git,
     KernelOrg,
 }
 
+enum Configurator {
+    None,
+    XConfig,
+    MenuConfig,
+    NConfig,
+}
+
+impl Configurator {
+    fn make_target(&self) -> &'static str {
+        match self {
+            Configurator::None => "",
+            Configurator::XConfig => "xconfig",
+            Configurator::MenuConfig => "menuconfig",
+            Configurator::NConfig => "nconfig",
+        }
+    }
+}
+
+// Which modules localmodconfig keeps
+enum LocalModules {
+    All,
+    Loaded,
+    // lsmod output saved on another machine
+    Snapshot(String),
+}
+
 struct BuildContext<'a> {
     ui: Arc<Mutex<UI<'a>>>,
+    profile: Profile,
+    // Passed to every make call, e.g. "LLVM=1"
+    make_vars: Vec<String>,
     source: Source,
 
     linux_tar_xz: String,
     source_dir: String,
     config: String,
+    // Picked by the user, instead of the one of the running kernel, which belongs to the package
+    config_is_file: bool,
+    // Merged into the config in order, like merge_config.sh does
+    fragments: Vec<String>,
+    local_modules: LocalModules,
     postfix: String,
-    xconfig: bool,
+    // "make kernelrelease" with LOCALVERSION=-<postfix>, known once the config is done
+    release: String,
+    // Relative to the source dir
+    image_name: String,
+    configurator: Configurator,
 }
 
 impl<'a> BuildContext<'a> {
+    // Once at startup. A broken profile can't be fixed by answering the prompts again
+    fn load_profile(&mut self) -> Result<()> {
+        self.profile = Profile::load()?;
+        if !self.profile.is_empty() {
+            let path = self.profile.path.clone();
+            self.ui().log().info(format!("Using profile {}", path));
+        }
+        self.make_vars = self.profile.make_vars()?;
+        self.profile.validate()?;
+        // A kernel for another machine can't be installed here
+        if self.arch() != host_arch() && self.profile.output_dir().is_none() {
+            return Err(anyhow!(
+                "Building for {} needs output_dir in the profile",
+                self.arch()
+            ));
+        }
+        Ok(())
+    }
+
     // Downloads source and prepares for build. Changes directory to the source dir
     pub fn prepare_source(&mut self) -> Result<()> {
-        self.ui
-            .lock()
-            .unwrap()
-            .log()
-            .append(String::from("Preparing source..."));
+        self.ui().log().info("Preparing source...");
 
         // Postfix (To differentiate kernel versions)
-        let mut postfix_input =
-            TextInput::new(TextInputType::String, "Please enter install postfix: ");
-        let postfix = postfix_input.output();
-        let future = self.ui().input().set(postfix_input.choice());
-        future.wait();
-        self.postfix = postfix.lock().unwrap().clone();
+        self.postfix = self.ask(
+            TextInput::new(TextInputType::String, "Please enter install postfix: "),
+            "postfix",
+        );
+
+        let log_file = self.ui().log().persist(&self.postfix);
+        match log_file {
+            Ok(path) => self.ui().log().info(format!("Writing log to {}", path)),
+            Err(err) => self
+                .ui()
+                .log()
+                .warn(format!("Failed to create log file: {}", err)),
+        }
 
         // Where the config is
-        let mut config_input =
-            TextInput::new(TextInputType::String, "Please enter .config location: ");
-        let config = config_input.output();
-        let future = self.ui().input().set(config_input.choice());
+        let mut config_source_input = EnumInput::new(
+            vec![String::from("Running kernel"), String::from("File")],
+            "Config source:",
+        );
+        let config_source_idx = config_source_input.output();
+        let future = self.ui().input().set(config_source_input.choice());
         future.wait();
+        let config_source_idx = *config_source_idx.lock().unwrap();
+        self.config_is_file = config_source_idx == 1;
+        let config = match config_source_idx {
+            0 => kconfig::running_config()
+                .ok_or(anyhow!("Couldn't find the config of the running kernel"))?,
+            _ => {
+                let mut config_input =
+                    TextInput::new(TextInputType::ConfigPath, "Please enter .config location: ");
+                if let Some(running) = kconfig::running_config() {
+                    config_input = config_input.with_default(&running.to_string_lossy());
+                }
+                let config = fs::canonicalize(self.prompt(config_input, "config"))?;
+                self.remember("config", &config.to_string_lossy());
+                config
+            }
+        };
 
-        let abs_config = fs::canonicalize(config.lock().unwrap().clone())?;
+        let abs_config = fs::canonicalize(config)?;
         self.config = String::from(abs_config.to_str().unwrap());
-        self.ui().log().append(self.config.clone());
+        self.ui().log().info(self.config.clone());
 
-        let mut xconfig_input = EnumInput::new(vec!["Yes".into(), "No".into()], "Open xconfig?");
-        let xconfig_idx = xconfig_input.output();
-        let future = self.ui().input().set(xconfig_input.choice());
+        let fragments = self.prompt(
+            TextInput::new(
+                TextInputType::String,
+                "Config fragments (space separated, may be empty): ",
+            ),
+            "fragments",
+        );
+        self.fragments.clear();
+        for fragment in fragments.split_whitespace() {
+            kconfig::check(fragment)?;
+            let fragment = fs::canonicalize(fragment)?;
+            self.fragments
+                .push(String::from(fragment.to_str().unwrap()));
+        }
+        if !self.fragments.is_empty() {
+            let fragments = self.fragments.join(" ");
+            self.ui().log().info(format!("Fragments: {}", fragments));
+            self.remember("fragments", &fragments);
+        }
+
+        let mut local_modules_input = EnumInput::new(
+            vec![
+                String::from("No"),
+                String::from("Loaded modules"),
+                String::from("lsmod snapshot file"),
+            ],
+            "Only keep used modules? (localmodconfig)",
+        );
+        let local_modules_idx = local_modules_input.output();
+        let future = self.ui().input().set(local_modules_input.choice());
         future.wait();
-        let idx = xconfig_idx.lock().unwrap();
-        self.xconfig = match *idx {
-            0 => true,
-            _ => false,
+        let local_modules_idx = *local_modules_idx.lock().unwrap();
+        self.local_modules = match local_modules_idx {
+            1 => LocalModules::Loaded,
+            2 => {
+                let snapshot = self.prompt(
+                    TextInput::new(TextInputType::String, "Please enter lsmod snapshot file: "),
+                    "lsmod",
+                );
+                let snapshot = String::from(fs::canonicalize(snapshot)?.to_str().unwrap());
+                self.remember("lsmod", &snapshot);
+                LocalModules::Snapshot(snapshot)
+            }
+            _ => LocalModules::All,
+        };
+
+        // menuconfig/nconfig work without X (e.g. over SSH)
+        let mut configurator_input = EnumInput::new(
+            vec![
+                "No".into(),
+                "xconfig".into(),
+                "menuconfig".into(),
+                "nconfig".into(),
+            ],
+            "Edit config?",
+        );
+        let configurator_idx = configurator_input.output();
+        let future = self.ui().input().set(configurator_input.choice());
+        future.wait();
+        let idx = configurator_idx.lock().unwrap();
+        self.configurator = match *idx {
+            1 => Configurator::XConfig,
+            2 => Configurator::MenuConfig,
+            3 => Configurator::NConfig,
+            _ => Configurator::None,
         };
 
         // Where the sources are
@@ -78,78 +225,573 @@ impl<'a> BuildContext<'a> {
         let future = self.ui().input().set(location_input.choice());
         future.wait();
         let idx = location_idx.lock().unwrap();
+        // Everything is answered, the panel showed the previous build until now
+        self.ui().pipeline().reset();
         match *idx {
             0 => {
                 self.source = Source::KernelOrg;
-                self.download_kernel_org()?;
-                self.extract()?;
-                self.verify()?;
-                self.untar()?;
+                self.stage(Stage::Download, |ctx| ctx.download_kernel_org())?;
+                self.stage(Stage::Extract, |ctx| {
+                    ctx.extract()?;
+                    ctx.verify()?;
+                    ctx.untar()
+                })?;
             }
             1 => {
                 self.source = Source::Git;
-                self.download_git()?;
+                self.stage(Stage::Download, |ctx| ctx.download_git())?;
+                // Cloned straight into the source dir
+                self.ui().pipeline().skip(Stage::Extract);
             }
             _ => return Err(anyhow!("Index out of bounds!")),
         };
 
+        self.stage(Stage::Configure, |ctx| ctx.configure())
+    }
+
+    // Prepares .config in the source dir
+    fn configure(&mut self) -> Result<()> {
         env::set_current_dir(self.source_dir.clone())?;
 
+        // Depends on the config, so it's figured out at the end
+        self.release = String::new();
+
         // Clean
-        self.ui().log().append(String::from("Cleaning..."));
-        run_process("make mrproper", &mut self.ui)?;
+        self.ui().log().info("Cleaning...");
+        run_process(self.make("mrproper").as_str(), &mut self.ui)?;
 
         // Copy config
-        fs::copy(&self.config, format!("{}/.config", self.source_dir))?;
+        fs::write(
+            format!("{}/.config", self.source_dir),
+            kconfig::read_text(&self.config)?,
+        )?;
+        // Before localmodconfig, its oldconfig would answer the questions about new options
+        self.new_options()?;
+        self.local_modules()?;
+        self.merge_fragments()?;
+        self.thin_lto()?;
+        self.check_fragments()?;
+
+        // Run the config editor
+        let cmd = self.make(self.configurator.make_target());
+        match self.configurator {
+            Configurator::None => {}
+            Configurator::XConfig => {
+                // Add root as xhost
+                run_process_user("xhost +si:localuser:root", &mut self.ui)?;
+                run_process(cmd.as_str(), &mut self.ui)?;
+            }
+            Configurator::MenuConfig | Configurator::NConfig => {
+                // These need the real terminal
+                run_process_attached(cmd.as_str(), &mut self.ui)?;
+            }
+        }
+
+        let diff = kconfig::diff(&kconfig::read(&self.config)?, &kconfig::read(".config")?);
+        if diff.is_empty() {
+            self.ui()
+                .log()
+                .info(format!("Config is the same as {}", self.config));
+        } else {
+            self.ui().log().info(format!(
+                "Config differs from {}: {}",
+                self.config,
+                diff.summary()
+            ));
+            for line in diff.lines() {
+                self.ui().log().info(format!("  {}", line));
+            }
+        }
+        // A config violating the policy isn't worth saving
+        self.check_policy()?;
+        if !diff.is_empty() {
+            if !matches!(self.configurator, Configurator::None) {
+                self.save_config()?;
+            }
+            self.review_config(&diff)?;
+        }
+
+        // Depends on the config, e.g. arch/x86/boot/bzImage
+        let image_name = capture_process(self.make("-s image_name").as_str(), &mut self.ui)?;
+        self.image_name = String::from(image_name.trim());
+
+        // With LOCALVERSION set, kbuild doesn't append "+" for trees that aren't a release tag.
+        // CONFIG_LOCALVERSION still ends up in there
+        let release = capture_process(
+            self.make(&format!(
+                "-s LOCALVERSION={} kernelrelease",
+                shell_quote(&format!("-{}", self.postfix))
+            ))
+            .as_str(),
+            &mut self.ui,
+        )?;
+        self.release = String::from(release.trim());
+        self.ui()
+            .log()
+            .info(format!("Kernel release: {}", self.release));
+        Ok(())
+    }
+
+    fn check_policy(&mut self) -> Result<()> {
+        let violations = self.profile.check_policy(&kconfig::read(".config")?);
+        if violations.is_empty() {
+            return Ok(());
+        }
+        self.ui().log().error(format!(
+            "Config violates the policy in {}:",
+            self.profile.path
+        ));
+        for violation in &violations {
+            self.ui().log().error(format!("  {}", violation));
+        }
+        Err(anyhow!("{} policy violations", violations.len()))
+    }
+
+    // Catches options dropped by a version bump, before spending the time compiling
+    fn review_config(&mut self, diff: &kconfig::Diff) -> Result<()> {
+        let lines = diff
+            .lines()
+            .into_iter()
+            .map(|line| {
+                let color = match line.chars().next() {
+                    Some('-') => Color::LightRed,
+                    Some('+') => Color::LightGreen,
+                    _ => Color::Yellow,
+                };
+                Line::styled(line, Style::default().fg(color))
+            })
+            .collect();
+        let mut review_input = ReviewInput::new(
+            lines,
+            format!("{} -> .config ({})", self.config, diff.summary()),
+        );
+        let accepted = review_input.output();
+        let future = self.ui().input().set(review_input.choice());
+        future.wait();
+        let accepted = *accepted.lock().unwrap();
+        match accepted {
+            true => Ok(()),
+            false => Err(anyhow!("Aborted after reviewing the config")),
+        }
+    }
+
+    // The edits would be lost with the tmpfs, so offer to write them back to the source config
+    fn save_config(&mut self) -> Result<()> {
+        // E.g. /proc/config.gz or /boot/config-<release>
+        let writable = self.config_is_file
+            && !self.config.ends_with(".gz")
+            && !self.config.starts_with("/proc/");
+        if !writable {
+            self.ui()
+                .log()
+                .info(format!("Can't write the config back to {}", self.config));
+            return Ok(());
+        }
+
+        if !self.fragments.is_empty() {
+            self.ui()
+                .log()
+                .warn("Saving writes the merged fragments into the config as well");
+        }
+        if !matches!(self.local_modules, LocalModules::All) {
+            self.ui()
+                .log()
+                .warn("Saving keeps only the modules localmodconfig left in the config");
+        }
+
+        let mut save_input = EnumInput::new(
+            vec![
+                String::from("No"),
+                String::from("Save .config"),
+                String::from("Save minimized (savedefconfig)"),
+            ],
+            format!("Save config to {}?", self.config),
+        );
+        let save_idx = save_input.output();
+        let future = self.ui().input().set(save_input.choice());
+        future.wait();
+        let save_idx = *save_idx.lock().unwrap();
+        let new_config = match save_idx {
+            1 => fs::read(".config")?,
+            2 => {
+                run_process(self.make("savedefconfig").as_str(), &mut self.ui)?;
+                fs::read("defconfig")?
+            }
+            _ => return Ok(()),
+        };
+
+        let backup = format!("{}.{}", self.config, log::timestamp("%Y-%m-%d_%H-%M-%S"));
+        fs::copy(&self.config, &backup)?;
+        self.ui()
+            .log()
+            .info(format!("Backed up {} to {}", self.config, backup));
+        // Writing instead of copying keeps the owner of the file
+        fs::write(&self.config, new_config)?;
+        self.ui()
+            .log()
+            .info(format!("Saved config to {}", self.config));
+        Ok(())
+    }
+
+    // Disables every module that isn't loaded. Runs before merging the fragments, so they can
+    // still enable modules.
+    fn local_modules(&mut self) -> Result<()> {
+        let lsmod = match &self.local_modules {
+            LocalModules::All => return Ok(()),
+            LocalModules::Loaded => String::new(),
+            LocalModules::Snapshot(path) => format!("LSMOD={} ", shell_quote(path)),
+        };
+        self.ui().log().info("Disabling unused modules");
+        // localmodconfig ends with oldconfig, which asks about symbols the config doesn't set
+        // (new_options handled the new ones already), take the defaults
+        run_process(
+            format!(
+                "yes '' | {}",
+                self.make(&format!("{}localmodconfig", lsmod))
+            )
+            .as_str(),
+            &mut self.ui,
+        )
+    }
+
+    fn merge_fragments(&mut self) -> Result<()> {
+        if self.fragments.is_empty() {
+            return Ok(());
+        }
+        let mut config = kconfig::read(".config")?;
+        for fragment in self.fragments.clone() {
+            self.ui().log().info(format!("Merging {}", fragment));
+            for (symbol, value) in kconfig::read(&fragment)? {
+                match config.insert(symbol.clone(), value.clone()) {
+                    Some(old) if old != value => self.ui().log().info(format!(
+                        "  CONFIG_{} redefined: {} -> {}",
+                        symbol, old, value
+                    )),
+                    _ => {}
+                }
+            }
+        }
+        fs::write(".config", kconfig::to_text(&config))?;
+        // Fragment values that don't fit the dependencies are fixed up, check_fragments tells
+        run_process(self.make("olddefconfig").as_str(), &mut self.ui)
+    }
+
+    // Fragment symbols with unmet dependencies get dropped by olddefconfig, without any notice
+    fn check_fragments(&mut self) -> Result<()> {
+        // Symbol -> value, fragment. Later fragments override earlier ones, like when merging
+        let mut requested = BTreeMap::new();
+        for fragment in &self.fragments {
+            for (symbol, value) in kconfig::read(fragment)? {
+                requested.insert(symbol, (value, fragment.clone()));
+            }
+        }
+
+        let config = kconfig::read(".config")?;
+        for (symbol, (value, fragment)) in requested {
+            let actual = config.get(&symbol).map(String::as_str).unwrap_or("n");
+            if actual != value {
+                self.ui().log().warn(format!(
+                    "CONFIG_{}={} from {} didn't make it into the config (is {}), check its dependencies",
+                    symbol, value, fragment, actual
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    // Options the config doesn't know yet (e.g. the running kernel is older) would make kbuild
+    // ask for them in the middle of the build, with nobody to answer
+    fn new_options(&mut self) -> Result<()> {
+        let new_options = capture_process(self.make("listnewconfig").as_str(), &mut self.ui)?;
+        // CONFIG_FOO=default
+        let new_options: Vec<(String, String)> = new_options
+            .lines()
+            .filter_map(|line| line.strip_prefix("CONFIG_")?.split_once('='))
+            .map(|(symbol, default)| (String::from(symbol), String::from(default)))
+            .collect();
+
+        if !new_options.is_empty() {
+            self.ui()
+                .log()
+                .info(format!("{} new config options:", new_options.len()));
+            for (symbol, default) in &new_options {
+                self.ui()
+                    .log()
+                    .info(format!("  CONFIG_{}={}", symbol, default));
+            }
+
+            let mut mode_input = EnumInput::new(
+                vec![
+                    String::from("Use defaults (olddefconfig)"),
+                    String::from("Answer each"),
+                ],
+                format!("{} new config options", new_options.len()),
+            );
+            let mode_idx = mode_input.output();
+            let future = self.ui().input().set(mode_input.choice());
+            future.wait();
+            let mode_idx = *mode_idx.lock().unwrap();
+            if mode_idx == 1 {
+                for (idx, (symbol, default)) in new_options.iter().enumerate() {
+                    let mut value_input = TextInput::new(
+                        TextInputType::String,
+                        format!("({}/{}) CONFIG_{}", idx + 1, new_options.len(), symbol),
+                    )
+                    .with_default(default);
+                    let value = value_input.output();
+                    let future = self.ui().input().set(value_input.choice());
+                    future.wait();
+                    let value = value.lock().unwrap().clone();
+                    if value.is_empty() || value == *default {
+                        continue;
+                    }
+
+                    // String options have a quoted default, their answers may leave out the quotes
+                    let string = value.strip_prefix('"').and_then(|v| v.strip_suffix('"'));
+                    let option = match string {
+                        Some(string) => format!("--set-str {} {}", symbol, shell_quote(string)),
+                        None if default.starts_with('"') => {
+                            format!("--set-str {} {}", symbol, shell_quote(&value))
+                        }
+                        None if value == "n" => format!("--disable {}", symbol),
+                        None => format!("--set-val {} {}", symbol, shell_quote(&value)),
+                    };
+                    run_process(
+                        format!("scripts/config --file .config {}", option).as_str(),
+                        &mut self.ui,
+                    )?;
+                }
+            }
+        }
+
+        // Everything not answered gets its default, answers that don't fit the dependencies are
+        // fixed up
+        run_process(self.make("olddefconfig").as_str(), &mut self.ui)
+    }
 
-        // Run xconfig
-        if self.xconfig {
-            // Add root as xhost
-            run_process_user("xhost +si:localuser:root", &mut self.ui)?;
+    // Needs clang, so it can only be enabled once the toolchain is passed to make
+    fn thin_lto(&mut self) -> Result<()> {
+        if !self.profile.lto()? {
+            return Ok(());
+        }
+        self.ui().log().info("Enabling ThinLTO");
+        run_process(
+            "scripts/config --file .config -d LTO_NONE -e LTO_CLANG_THIN",
+            &mut self.ui,
+        )?;
+        run_process(self.make("olddefconfig").as_str(), &mut self.ui)?;
+        if !kconfig::is_enabled(&kconfig::read(".config")?, "LTO_CLANG_THIN") {
+            self.ui()
+                .log()
+                .warn("ThinLTO is not supported by this kernel/architecture");
+        }
+        Ok(())
+    }
+
+    pub fn build(&mut self) -> Result<()> {
+        self.stage(Stage::Compile, |ctx| ctx.compile())
+    }
+
+    fn compile(&mut self) -> Result<()> {
+        // Same config -> same number of objects as last time
+        let config = fs::read(".config")?;
+        let config_hash = progress::config_hash(&config);
+        let progress = match state::get("objects", &config_hash)
+            .and_then(|objects| objects.parse().ok())
+        {
+            Some(objects) => Progress::new(objects, false),
+            None => {
+                let config = kconfig::parse(&String::from_utf8_lossy(&config));
+                let objects =
+                    progress::estimate_objects(Path::new(&self.source_dir), &self.arch(), &config);
+                Progress::new(objects, true)
+            }
+        };
+        let ccache = self.profile.ccache()?;
+        if let Some(ccache) = &ccache {
+            self.prepare_ccache(ccache)?;
+        }
+
+        // Everything in one make call, so the jobs are shared between the targets
+        let config = kconfig::read(".config")?;
+        let mut targets = vec![Path::new(&self.image_name)
+            .file_name()
+            .ok_or(anyhow!("Invalid image name: {}", self.image_name))?
+            .to_string_lossy()
+            .into_owned()];
+        if kconfig::is_enabled(&config, "MODULES") {
+            targets.push(String::from("modules"));
+        }
+        if kconfig::is_enabled(&config, "OF")
+            && Path::new(&format!("arch/{}/boot/dts", self.arch())).is_dir()
+        {
+            targets.push(String::from("dtbs"));
+        }
+        let cmd = self.make(&format!("{} {}", self.profile.jobs(), targets.join(" ")));
+        let cmd = self.profile.limit(&cmd);
+        *self.ui().progress() = Some(progress);
+        let result = run_process(cmd.as_str(), &mut self.ui);
+        let progress = self.ui().progress().take();
+        if let Some(ccache) = &ccache {
+            // Only informational, mustn't hide the build result
+            if let Some(err) = self.ccache_stats(ccache).err() {
+                self.ui()
+                    .log()
+                    .warn(format!("Failed to read ccache stats: {}", err));
+            }
+        }
+        result?;
+
+        if let Some(progress) = progress {
+            if let Some(err) =
+                state::set("objects", &config_hash, &progress.compiled().to_string()).err()
+            {
+                self.ui()
+                    .log()
+                    .warn(format!("Failed to save object count: {}", err));
+            }
+        }
+        Ok(())
+    }
+
+    fn prepare_ccache(&mut self, ccache: &profile::Ccache) -> Result<()> {
+        fs::create_dir_all(&ccache.dir)?;
+        let env = format!("CCACHE_DIR={}", shell_quote(&ccache.dir));
+        if let Some(max_size) = &ccache.max_size {
             run_process(
-                format!(
-                    "make KERNELRELEASE=\"$(make -s kernelversion)-{postfix}\" xconfig",
-                    postfix = self.postfix
-                )
-                .as_str(),
+                format!("{} ccache -M {}", env, shell_quote(max_size)).as_str(),
                 &mut self.ui,
             )?;
         }
+        // Only count this build in the stats
+        run_process(format!("{} ccache -z", env).as_str(), &mut self.ui)
+    }
 
+    fn ccache_stats(&mut self, ccache: &profile::Ccache) -> Result<()> {
+        let stats = capture_process(
+            format!(
+                "CCACHE_DIR={} ccache --print-stats",
+                shell_quote(&ccache.dir)
+            )
+            .as_str(),
+            &mut self.ui,
+        )?;
+        // "key\tvalue" lines
+        let stat = |key: &str| -> u64 {
+            stats
+                .lines()
+                .filter_map(|line| line.split_once('\t'))
+                .find(|(stat_key, _)| *stat_key == key)
+                .and_then(|(_, value)| value.trim().parse().ok())
+                .unwrap_or(0)
+        };
+        let hits = stat("direct_cache_hit") + stat("preprocessed_cache_hit");
+        let total = hits + stat("cache_miss");
+        if total == 0 {
+            return Ok(());
+        }
+        self.ui().log().info(format!(
+            "ccache: {} of {} compilations cached ({:.1}%)",
+            hits,
+            total,
+            hits as f64 * 100.0 / total as f64
+        ));
         Ok(())
     }
 
-    pub fn build(&mut self) -> Result<()> {
-        self.ui().log().append(String::from("Compiling kernel"));
-        let cmd = format!("make KERNELRELEASE=\"$(make -s kernelversion)-{postfix}\" -j$(nproc) && make KERNELRELEASE=\"$(make -s kernelversion)-{postfix}\" modules -j$(nproc)", postfix = self.postfix);
+    pub fn install(&mut self) -> Result<()> {
+        if let Some(output_dir) = self.profile.output_dir().map(String::from) {
+            self.stage(Stage::Install, |ctx| ctx.export(&output_dir))?;
+            // Both only make sense on the machine that boots the kernel
+            self.ui().pipeline().skip(Stage::Dkms);
+            self.ui().pipeline().skip(Stage::Initramfs);
+            return Ok(());
+        }
+        self.stage(Stage::Install, |ctx| ctx.install_kernel())?;
+        // I don't care about dynamic DKMS support, if I need to recompile DKMS I just can
+        // recompile the kernel. This allows for the exclusion of ~60MiB of headers in the final
+        // install
+        self.stage(Stage::Dkms, |ctx| {
+            ctx.nvidia_dkms()?;
+            ctx.remove_build_links()
+        })?;
+        self.stage(Stage::Initramfs, |ctx| ctx.mkinitcpio())
+    }
+
+    // Copies everything needed to boot the kernel on another machine to output_dir/<release>
+    fn export(&mut self, output_dir: &str) -> Result<()> {
+        let dir = format!("{}/{}", output_dir, self.release);
+        self.ui().log().info(format!("Exporting kernel to {}", dir));
+        if Path::new(&dir).exists() {
+            fs::remove_dir_all(&dir)?;
+        }
+        fs::create_dir_all(&dir)?;
+
+        run_process(
+            format!("cp {} {}/", self.image_name, shell_quote(&dir)).as_str(),
+            &mut self.ui,
+        )?;
+        fs::copy(".config", format!("{}/config", dir))?;
+
+        self.ui().log().info("Exporting Kernel modules");
+        let cmd = format!(
+            "ZSTD_CLEVEL=19 {}",
+            self.make(&format!(
+                "INSTALL_MOD_PATH={} INSTALL_MOD_STRIP=1 modules_install {}",
+                shell_quote(&dir),
+                self.profile.jobs()
+            ))
+        );
         run_process(cmd.as_str(), &mut self.ui)?;
+        // They point into the tmpfs
+        run_process(
+            format!("rm -f {}/lib/modules/*/{{source,build}}", shell_quote(&dir)).as_str(),
+            &mut self.ui,
+        )?;
+
+        // Boards need their device trees
+        if kconfig::is_enabled(&kconfig::read(".config")?, "OF") {
+            self.ui().log().info("Exporting device trees");
+            run_process(
+                self.make(&format!(
+                    "INSTALL_DTBS_PATH={}/dtbs dtbs_install",
+                    shell_quote(&dir)
+                ))
+                .as_str(),
+                &mut self.ui,
+            )?;
+        }
         Ok(())
     }
 
-    pub fn install(&mut self) -> Result<()> {
+    fn install_kernel(&mut self) -> Result<()> {
         // Remove old modules, to avoid keeping stale mods into all eternity
         self.ui()
             .log()
-            .append("Removing old Kernel modules from /usr/lib/modules".into());
+            .info("Removing old Kernel modules from /usr/lib/modules");
         // Delete everything that ends in "-{postfix}", so we clean old versions.
         // Don't care if this fails, since rm fails when directory doesn't exist.
         let _ = run_process(format!("rm -rf *-{}", self.postfix).as_str(), &mut self.ui);
 
         // Install modules
-        self.ui().log().append(String::from(
-            "Installing Kernel modules to /usr/lib/modules",
-        ));
-        let cmd = format!("ZSTD_CLEVEL=19 make KERNELRELEASE=\"$(make -s kernelversion)-{}\" INSTALL_MOD_STRIP=1 modules_install -j$(nproc)", self.postfix);
+        self.ui()
+            .log()
+            .info("Installing Kernel modules to /usr/lib/modules");
+        let cmd = format!(
+            "ZSTD_CLEVEL=19 {}",
+            self.make(&format!(
+                "INSTALL_MOD_STRIP=1 modules_install {}",
+                self.profile.jobs()
+            ))
+        );
         run_process(cmd.as_str(), &mut self.ui)?;
 
         // Install vmlinuz
-        self.ui()
-            .log()
-            .append(String::from("Installing Kernel to /boot"));
+        self.ui().log().info("Installing Kernel to /boot");
         run_process(
             format!(
-                "cp $(make -s image_name) /boot/vmlinuz-linux-{}",
-                self.postfix
+                "cp {} /boot/vmlinuz-linux-{}",
+                self.image_name, self.postfix
             )
             .as_str(),
             &mut self.ui,
@@ -158,53 +800,42 @@ impl<'a> BuildContext<'a> {
         // From arch PKGBUILD: "systemd expects to find the kernel here to allow hibernation"
         self.ui()
             .log()
-            .append(String::from("Installing Kernel to /usr/lib/modules"));
+            .info("Installing Kernel to /usr/lib/modules");
         run_process(
             format!(
-                "cp $(make -s image_name) /usr/lib/modules/$(make -s kernelversion)-{}/vmlinuz",
-                self.postfix
+                "cp {} /usr/lib/modules/{}/vmlinuz",
+                self.image_name, self.release
             )
             .as_str(),
             &mut self.ui,
         )?;
+        Ok(())
+    }
 
-        // I don't care about dynamic DKMS support, if I need to recompile DKMS I just can
-        // recompile the kernel. This allows for the exclusion of ~60MiB of headers in the final
-        // install
-        self.nvidia_dkms()?;
-
-        // Unlink
+    fn remove_build_links(&mut self) -> Result<()> {
         self.ui()
             .log()
-            .append(String::from("Removing symlinks in /usr/lib/modules"));
+            .info("Removing symlinks in /usr/lib/modules");
         run_process(
-            format!(
-                "rm /usr/lib/modules/$(make -s kernelversion)-{}/{{source,build}}",
-                self.postfix
-            )
-            .as_str(),
+            format!("rm /usr/lib/modules/{}/{{source,build}}", self.release).as_str(),
             &mut self.ui,
         )?;
-
-        self.mkinitcpio()?;
-
         Ok(())
     }
 
     fn download_kernel_org(&mut self) -> Result<()> {
         // Ask for version
-        let mut version_input =
-            TextInput::new(TextInputType::Version, "Please select kernel version: ");
-        let version = version_input.output();
-        let future = self.ui().input().set(version_input.choice());
-        future.wait();
+        let version = self.ask(
+            TextInput::new(TextInputType::Version, "Please select kernel version: "),
+            "version",
+        );
 
         // Check if exists
-        self.linux_tar_xz = format!("/tmp/linux/linux-{}.tar.xz", version.lock().unwrap());
+        self.linux_tar_xz = format!("/tmp/linux/linux-{}.tar.xz", version);
         let mut linux_tar = PathBuf::from(&self.linux_tar_xz);
         linux_tar.set_extension("");
         if Path::new(&self.linux_tar_xz).exists() || linux_tar.exists() {
-            self.ui().log().append(format!(
+            self.ui().log().info(format!(
                 "{} already exists, skipping download",
                 self.linux_tar_xz
             ));
@@ -215,9 +846,9 @@ impl<'a> BuildContext<'a> {
         download(
             format!(
                 "https://cdn.kernel.org/pub/linux/kernel/v6.x/linux-{}.tar.xz",
-                version.lock().unwrap()
+                version
             ),
-            format!("/tmp/linux/linux-{}.tar.xz", version.lock().unwrap()),
+            format!("/tmp/linux/linux-{}.tar.xz", version),
             self.ui.clone(),
         )?;
 
@@ -225,11 +856,11 @@ impl<'a> BuildContext<'a> {
     }
 
     fn download_git(&mut self) -> Result<()> {
-        let mut repo_input = TextInput::new(TextInputType::String, "Please enter git repository: ");
-        let repo = repo_input.output();
-        let future = self.ui().input().set(repo_input.choice());
-        future.wait();
-        self.ui().log().append(repo.lock().unwrap().clone());
+        let repo = self.ask(
+            TextInput::new(TextInputType::String, "Please enter git repository: "),
+            "repo",
+        );
+        self.ui().log().info(repo.clone());
 
         fs::create_dir_all("/tmp/linux")?;
         self.source_dir = String::from("/tmp/linux/linux");
@@ -238,18 +869,13 @@ impl<'a> BuildContext<'a> {
         if Path::new("/tmp/linux/linux").is_dir() {
             self.ui()
                 .log()
-                .append("/tmp/linux/linux already exists, skipping download".into());
+                .info("/tmp/linux/linux already exists, skipping download");
             // Already downloaded
             return Ok(());
         }
 
         run_process(
-            format!(
-                "git clone --depth=1 {} {}",
-                repo.lock().unwrap(),
-                self.source_dir
-            )
-            .as_str(),
+            format!("git clone --depth=1 {} {}", repo, self.source_dir).as_str(),
             &mut self.ui,
         )?;
         Ok(())
@@ -259,7 +885,7 @@ impl<'a> BuildContext<'a> {
         let mut buf = PathBuf::from(self.linux_tar_xz.as_str());
         buf.set_extension("");
         if buf.exists() {
-            self.ui().log().append(format!(
+            self.ui().log().info(format!(
                 "{} already exists, skipping extract!",
                 buf.into_os_string().into_string().unwrap()
             ));
@@ -268,11 +894,11 @@ impl<'a> BuildContext<'a> {
 
         self.ui()
             .log()
-            .append(format!("Extracting {}", self.linux_tar_xz));
+            .info(format!("Extracting {}", self.linux_tar_xz));
         run_process(format!("unxz {}", self.linux_tar_xz).as_str(), &mut self.ui)
     }
     fn verify(&mut self) -> Result<()> {
-        self.ui().log().append(String::from("Verify: TODO!"));
+        self.ui().log().info("Verify: TODO!");
         Ok(())
     }
 
@@ -287,44 +913,127 @@ impl<'a> BuildContext<'a> {
         let tar = format!("{}.tar", self.source_dir);
 
         if dir.exists() {
-            self.ui().log().append(format!(
+            self.ui().log().info(format!(
                 "{} already exists, skipping untar!",
                 self.source_dir
             ));
             return Ok(());
         }
-        self.ui().log().append(format!("Untaring {}", tar));
+        self.ui().log().info(format!("Untaring {}", tar));
         run_process(
             format!("tar -xvf {} -C /tmp/linux", tar).as_str(),
             &mut self.ui,
         )
     }
 
+    // The name of the target architecture in arch/
+    fn arch(&self) -> String {
+        match self.profile.get("arch") {
+            Some("x86_64" | "i386") => String::from("x86"),
+            Some(arch) => String::from(arch),
+            None => String::from(host_arch()),
+        }
+    }
+
+    // Every make call goes through here, since mixing toolchains between calls breaks the build
+    fn make(&self, args: &str) -> String {
+        let mut cmd = String::from("make");
+        // Only unknown while figuring it out
+        if !self.release.is_empty() {
+            cmd.push_str(&format!(" KERNELRELEASE={}", shell_quote(&self.release)));
+        }
+        for var in &self.make_vars {
+            cmd.push(' ');
+            cmd.push_str(var);
+        }
+        if !args.is_empty() {
+            cmd.push(' ');
+            cmd.push_str(args);
+        }
+        cmd
+    }
+
     fn ui(&self) -> MutexGuard<'_, UI<'a>> {
         self.ui.lock().unwrap()
     }
 
+    // Shows the prompt with the earlier answers to it, and remembers the new answer
+    fn ask(&mut self, input: TextInput<'a>, history_key: &str) -> String {
+        let answer = self.prompt(input, history_key);
+        self.remember(history_key, &answer);
+        answer
+    }
+
+    // Offers the earlier answers, without saving this one. For answers that need cleaning up
+    // first, e.g. relative paths
+    fn prompt(&mut self, input: TextInput<'a>, history_key: &str) -> String {
+        let mut input = input.with_history(state::get_all(HISTORY, history_key));
+        let output = input.output();
+        let future = self.ui().input().set(input.choice());
+        future.wait();
+        let answer = output.lock().unwrap().clone();
+        answer
+    }
+
+    fn remember(&mut self, history_key: &str, answer: &str) {
+        if answer.is_empty() {
+            return;
+        }
+        if let Some(err) = state::push(HISTORY, history_key, answer, HISTORY_LEN).err() {
+            self.ui()
+                .log()
+                .warn(format!("Failed to save input history: {}", err));
+        }
+    }
+
+    // Runs one step of the pipeline, keeping the overview panel and the log stage up to date
+    fn stage<T>(&mut self, stage: Stage, run: impl FnOnce(&mut Self) -> Result<T>) -> Result<T> {
+        {
+            let mut ui = self.ui();
+            ui.pipeline().start(stage);
+            ui.log().begin_stage(stage.name());
+        }
+        let result = run(self);
+        self.ui().pipeline().finish(stage, result.is_ok());
+        result
+    }
+
     fn nvidia_dkms(&mut self) -> Result<()> {
-        self.ui().log().append("Building nvidia dkms module".into());
+        self.ui().log().info("Building nvidia dkms module");
         // Output: nvidia-dkms xxx.xx-x
         // We only care about xxx.xx
-        let gather_nvidia_ver = "pacman -Q nvidia-dkms | grep -oP \"[0-9]*\\.[0-9]*\\.?[0-9]*\"";
+        let package = capture_process("pacman -Q nvidia-dkms", &mut self.ui)?;
+        let version = package
+            .split_whitespace()
+            .nth(1)
+            .and_then(|version| version.rsplit_once('-'))
+            .map(|(version, _pkgrel)| version)
+            .ok_or(anyhow!("Unexpected pacman output: {}", package.trim()))?;
+        // The dkms module is called nvidia/xxx.xx, without the epoch
+        let module = format!(
+            "nvidia/{}",
+            version.rsplit_once(':').map_or(version, |(_epoch, v)| v)
+        );
 
         // Remove old version
         run_process(
             format!(
-                "dkms remove nvidia/$({}) -k $(make -s kernelversion)-{}",
-                gather_nvidia_ver, self.postfix
+                "{} dkms remove {} -k {}",
+                self.make_vars.join(" "),
+                shell_quote(&module),
+                self.release
             )
             .as_str(),
             &mut self.ui,
         )?;
 
-        // Build
+        // Build, dkms passes the environment on to make
         run_process(
             format!(
-                "dkms install nvidia/$({}) -k $(make -s kernelversion)-{}",
-                gather_nvidia_ver, self.postfix
+                "{} dkms install {} -k {}",
+                self.make_vars.join(" "),
+                shell_quote(&module),
+                self.release
             )
             .as_str(),
             &mut self.ui,
@@ -335,14 +1044,14 @@ impl<'a> BuildContext<'a> {
 
     // Assumes, that preset linux-{postfix} is available
     fn mkinitcpio(&mut self) -> Result<()> {
-        self.ui().log().append("Generating initramfs".into());
+        self.ui().log().info("Generating initramfs");
         if run_process(
             format!("mkinitcpio -p linux-{}", self.postfix).as_str(),
             &mut self.ui,
         )
         .is_err()
         {
-            self.ui().log().append(format!(
+            self.ui().log().warn(format!(
                 "Failed to generate mkinitcpio! Does /etc/mkinitcpio.d/linux-{}.preset exist?",
                 self.postfix
             ));
@@ -351,61 +1060,85 @@ impl<'a> BuildContext<'a> {
     }
 
     pub fn clean(&mut self) -> Result<()> {
-        self.ui().log().append("Cleaning tmpfs".into());
-        run_process("rm -rf /tmp/linux", &mut self.ui)?;
-        Ok(())
+        self.stage(Stage::Clean, |ctx| {
+            ctx.ui().log().info("Cleaning tmpfs");
+            run_process("rm -rf /tmp/linux", &mut ctx.ui)
+        })
+    }
+}
+
+// The name of the host architecture in arch/
+fn host_arch() -> &'static str {
+    match env::consts::ARCH {
+        "x86" | "x86_64" => "x86",
+        "aarch64" => "arm64",
+        "riscv64" => "riscv",
+        "powerpc64" => "powerpc",
+        arch => arch,
     }
 }
 
 pub fn build_thread<'a>(ui: Arc<Mutex<UI<'a>>>) {
     let mut ctx: BuildContext<'a> = BuildContext {
         ui,
+        profile: Profile::default(),
+        make_vars: Vec::new(),
         source: Source::Git,
         linux_tar_xz: String::new(),
         source_dir: String::new(),
         config: String::new(),
+        config_is_file: false,
+        fragments: Vec::new(),
+        local_modules: LocalModules::All,
         postfix: String::new(),
-        xconfig: false,
+        release: String::new(),
+        image_name: String::new(),
+        configurator: Configurator::None,
     };
 
     // Check for root. We need root for installing
     unsafe {
         if libc::getuid() != 0 {
-            ctx.ui()
-                .log()
-                .append(String::from("rkbuild needs root privileges!"));
+            ctx.ui().log().error("rkbuild needs root privileges!");
             return;
         }
     }
 
+    if let Some(err) = ctx.load_profile().err() {
+        ctx.ui().log().error(format!(
+            "Invalid profile, fix it and restart rkbuild: {}",
+            err
+        ));
+        return;
+    }
+
     loop {
-        ctx.ui()
-            .log()
-            .append(String::from("rkbuild - Linux kernel build TUI"));
+        ctx.ui().log().begin_run();
+        ctx.ui().log().info("rkbuild - Linux kernel build TUI");
         if let Some(err) = ctx.prepare_source().err() {
             ctx.ui()
                 .log()
-                .append(format!("Error preparing source: {}", err));
+                .error(format!("Error preparing source: {}", err));
             continue;
         }
         if let Some(err) = ctx.build().err() {
             ctx.ui()
                 .log()
-                .append(format!("Error building kernel: {}", err));
+                .error(format!("Error building kernel: {}", err));
             continue;
         }
         if let Some(err) = ctx.install().err() {
             ctx.ui()
                 .log()
-                .append(format!("Error installing kernel: {}", err));
+                .error(format!("Error installing kernel: {}", err));
             continue;
         }
         if let Some(err) = ctx.clean().err() {
             ctx.ui()
                 .log()
-                .append(format!("Error cleaning tmpfs: {}", err));
+                .error(format!("Error cleaning tmpfs: {}", err));
             continue;
         }
-        ctx.ui().log().append(String::from("Done!"));
+        ctx.ui().log().info("Done!");
     }
 }
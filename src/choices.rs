@@ -1,26 +1,114 @@
-use std::sync::{Arc, Mutex};
+use std::{
+    fs,
+    sync::{Arc, Mutex},
+};
 
-use crossterm::event::{KeyCode, KeyEvent};
+use crossterm::event::{KeyCode, KeyEvent, KeyModifiers};
 use ratatui::{
     prelude::Rect,
+    prelude::{Constraint, Direction, Layout},
     style::{Color, Style},
-    text::Line,
-    widgets::{Block, Borders, List, ListItem, ListState, Paragraph},
+    text::{Line, Span},
+    widgets::{Block, Borders, List, ListItem, ListState, Paragraph, Wrap},
     Frame,
 };
 
 use crate::{
-    tui,
+    kconfig, tui,
     user::{Choice, ChoiceResult},
 };
 
 pub enum TextInputType {
     String,
     Version,
+    // Path to a kernel config, with Tab completion
+    ConfigPath,
+}
+// Single line editor, the cursor counts chars, not bytes
+#[derive(Default)]
+pub struct LineEditor {
+    chars: Vec<char>,
+    cursor: usize,
+    // First visible char, for inputs longer than the box
+    scroll: usize,
 }
+
+impl LineEditor {
+    pub fn text(&self) -> String {
+        self.chars.iter().collect()
+    }
+
+    pub fn set_text(&mut self, text: &str) {
+        self.chars = text.chars().collect();
+        self.cursor = self.chars.len();
+    }
+
+    // Returns true if the key was used. Only chars passing accept get inserted
+    pub fn handle_key(&mut self, key: &KeyEvent, accept: impl Fn(char) -> bool) -> bool {
+        let ctrl = key.modifiers.contains(KeyModifiers::CONTROL);
+        match key.code {
+            KeyCode::Left => self.cursor = self.cursor.saturating_sub(1),
+            KeyCode::Right => self.cursor = (self.cursor + 1).min(self.chars.len()),
+            KeyCode::Home => self.cursor = 0,
+            KeyCode::End => self.cursor = self.chars.len(),
+            KeyCode::Backspace if self.cursor > 0 => {
+                self.cursor -= 1;
+                self.chars.remove(self.cursor);
+            }
+            KeyCode::Delete if self.cursor < self.chars.len() => {
+                self.chars.remove(self.cursor);
+            }
+            // Kill the word before the cursor, like a shell does
+            KeyCode::Char('w') if ctrl => {
+                let mut start = self.cursor;
+                while start > 0 && self.chars[start - 1].is_whitespace() {
+                    start -= 1;
+                }
+                while start > 0 && !self.chars[start - 1].is_whitespace() {
+                    start -= 1;
+                }
+                self.chars.drain(start..self.cursor);
+                self.cursor = start;
+            }
+            // Kill everything before the cursor
+            KeyCode::Char('u') if ctrl => {
+                self.chars.drain(..self.cursor);
+                self.cursor = 0;
+            }
+            KeyCode::Char(c) if !ctrl && accept(c) => {
+                self.chars.insert(self.cursor, c);
+                self.cursor += 1;
+            }
+            _ => return false,
+        }
+        true
+    }
+
+    pub fn draw(&mut self, frame: &mut Frame<tui::Backend>, area: Rect, block: Block) {
+        // Keep the cursor inside the box
+        let width = (area.width.saturating_sub(2) as usize).max(1);
+        if self.cursor < self.scroll {
+            self.scroll = self.cursor;
+        } else if self.cursor >= self.scroll + width {
+            self.scroll = self.cursor + 1 - width;
+        }
+        let visible: String = self.chars.iter().skip(self.scroll).take(width).collect();
+        frame.render_widget(Paragraph::new(visible).block(block), area);
+        frame.set_cursor(area.x + 1 + (self.cursor - self.scroll) as u16, area.y + 1);
+    }
+}
+
 pub struct TextInput<'a> {
     choice: Option<Choice<'a>>,
     str: Arc<Mutex<String>>,
+    // Earlier answers, oldest first
+    history: Arc<Mutex<Vec<String>>>,
+}
+
+// Scrollable list of lines to look at, before continuing or aborting
+pub struct ReviewInput<'a> {
+    choice: Option<Choice<'a>>,
+    accepted: Arc<Mutex<bool>>,
 }
 
 pub struct EnumInput<'a> {
@@ -28,55 +116,188 @@ pub struct EnumInput<'a> {
     chosen_idx: Arc<Mutex<usize>>,
 }
 
+// Splits "/usr/li" into ("/usr/", "li")
+fn split_path(text: &str) -> (&str, &str) {
+    match text.rfind('/') {
+        Some(idx) => (&text[..=idx], &text[idx + 1..]),
+        None => ("", text),
+    }
+}
+
+// Entries of the directory in text, that start with the last path component. Directories end
+// in '/'
+fn path_candidates(text: &str) -> Vec<String> {
+    let (dir, prefix) = split_path(text);
+    let Ok(entries) = fs::read_dir(if dir.is_empty() { "." } else { dir }) else {
+        return vec![];
+    };
+    let mut names: Vec<String> = entries
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| {
+            let mut name = entry.file_name().into_string().ok()?;
+            // Hidden files only when asked for
+            if !name.starts_with(prefix) || (prefix.is_empty() && name.starts_with('.')) {
+                return None;
+            }
+            if entry.path().is_dir() {
+                name.push('/');
+            }
+            Some(name)
+        })
+        .collect();
+    names.sort();
+    names
+}
+
+// Completes the last path component as far as it is unambiguous
+fn complete_path(text: &str) -> String {
+    let candidates = path_candidates(text);
+    let Some(first) = candidates.first() else {
+        return String::from(text);
+    };
+    let mut common = first.as_str();
+    for candidate in &candidates[1..] {
+        while !candidate.starts_with(common) {
+            common = &common[..common.len() - common.chars().last().unwrap().len_utf8()];
+        }
+    }
+    format!("{}{}", split_path(text).0, common)
+}
+
 impl<'a> TextInput<'a> {
-    pub fn new(input_type: TextInputType, title: &'static str) -> Self {
+    pub fn new(input_type: TextInputType, title: impl Into<String>) -> Self {
+        let title = title.into();
         let string = Arc::new(Mutex::new(String::new()));
         let str_mem = string.clone();
-        let mut cursor_posx: u16 = 0;
+        let history: Arc<Mutex<Vec<String>>> = Arc::new(Mutex::new(Vec::new()));
+        let history_mem = history.clone();
+        // Position in the history, history.len() is the line being edited
+        let mut history_idx = 0;
+        // The line being edited, while browsing the history
+        let mut draft = String::new();
+        // Created on the first render, so a default set after new() is picked up
+        let mut editor: Option<LineEditor> = None;
+        // Shown instead of the candidates, until the input changes
+        let mut error: Option<String> = None;
+        // Directory listing for the current input, only re-read when the input changes
+        let mut candidates: (String, Vec<String>) = (String::new(), vec![]);
         let render_func =
             move |frame: &mut Frame<tui::Backend>, area: Rect, queue: &Option<Vec<KeyEvent>>| {
+                let history = history.lock().unwrap();
+                let editor = editor.get_or_insert_with(|| {
+                    history_idx = history.len();
+                    let mut editor = LineEditor::default();
+                    editor.set_text(&string.lock().unwrap());
+                    editor
+                });
+                let accept = |c: char| {
+                    c.is_numeric() || c == '.' || !matches!(input_type, TextInputType::Version)
+                };
+                let is_path = matches!(input_type, TextInputType::ConfigPath);
                 if let Some(queue) = queue {
                     for key in queue {
                         match key.code {
-                            KeyCode::Enter => return ChoiceResult::Remove,
-                            KeyCode::Backspace => {
-                                let mut string = string.lock().unwrap();
-                                if string.len() > 0 {
-                                    string.pop();
-                                    cursor_posx -= 1;
+                            KeyCode::Enter => {
+                                let text = editor.text();
+                                if is_path {
+                                    if let Some(err) = kconfig::check(&text).err() {
+                                        error = Some(err.to_string());
+                                        continue;
+                                    }
+                                }
+                                *string.lock().unwrap() = text;
+                                return ChoiceResult::Remove;
+                            }
+                            KeyCode::Up if history_idx > 0 => {
+                                if history_idx == history.len() {
+                                    draft = editor.text();
                                 }
+                                history_idx -= 1;
+                                editor.set_text(&history[history_idx]);
+                                error = None;
                             }
-                            KeyCode::Char(c) => {
-                                if c.is_numeric()
-                                    || c == '.'
-                                    || matches!(input_type, TextInputType::String)
-                                {
-                                    string.lock().unwrap().push(c);
-                                    cursor_posx += 1;
+                            KeyCode::Down if history_idx < history.len() => {
+                                history_idx += 1;
+                                match history.get(history_idx) {
+                                    Some(text) => editor.set_text(text),
+                                    None => editor.set_text(&draft),
+                                }
+                                error = None;
+                            }
+                            KeyCode::Tab if is_path => {
+                                editor.set_text(&complete_path(&editor.text()));
+                                error = None;
+                            }
+                            _ => {
+                                if editor.handle_key(key, accept) {
+                                    error = None;
                                 }
                             }
-                            _ => {}
                         }
                     }
                 }
-                let text = Paragraph::new(string.lock().unwrap().clone()).block(
+                let block = Block::default()
+                    .borders(Borders::ALL)
+                    .title(title.clone())
+                    .border_style(Style::default().fg(Color::LightGreen));
+                if !is_path {
+                    editor.draw(frame, area, block);
+                    return ChoiceResult::Continue;
+                }
+
+                let chunks = Layout::default()
+                    .direction(Direction::Vertical)
+                    .constraints([Constraint::Length(3), Constraint::Min(0)])
+                    .split(area);
+                editor.draw(frame, chunks[0], block);
+                if let Some(error) = &error {
+                    let text = Paragraph::new(error.clone())
+                        .style(Style::default().fg(Color::LightRed))
+                        .wrap(Wrap { trim: false });
+                    frame.render_widget(text, chunks[1]);
+                    return ChoiceResult::Continue;
+                }
+                let text = editor.text();
+                if candidates.0 != text {
+                    candidates = (text.clone(), path_candidates(&text));
+                }
+                let items: Vec<ListItem> = candidates
+                    .1
+                    .iter()
+                    .map(|name| ListItem::new(name.clone()))
+                    .collect();
+                let list = List::new(items).block(
                     Block::default()
                         .borders(Borders::ALL)
-                        .title(title)
-                        .border_style(Style::default().fg(Color::LightGreen)),
+                        .title(format!("{} (Tab)", split_path(&text).0)),
                 );
-                frame.render_widget(text, area);
-
-                frame.set_cursor(area.x + cursor_posx + 1, area.y + 1);
+                frame.render_widget(list, chunks[1]);
                 ChoiceResult::Continue
             };
 
         TextInput {
             choice: Some(Choice::new(render_func)),
             str: str_mem,
+            history: history_mem,
         }
     }
 
+    // Prefills the input
+    pub fn with_default(self, text: &str) -> Self {
+        *self.str.lock().unwrap() = String::from(text);
+        self
+    }
+
+    // Up/Down go through the history, the latest entry is the default
+    pub fn with_history(self, history: Vec<String>) -> Self {
+        let input = match history.last() {
+            Some(latest) => self.with_default(latest),
+            None => self,
+        };
+        *input.history.lock().unwrap() = history;
+        input
+    }
+
     pub fn choice(&mut self) -> Choice<'a> {
         self.choice.take().expect("Choice can only be called once!")
     }
@@ -87,7 +308,8 @@ impl<'a> TextInput<'a> {
 }
 
 impl<'a> EnumInput<'a> {
-    pub fn new(choices: Vec<String>, title: &'static str) -> Self {
+    pub fn new(choices: Vec<String>, title: impl Into<String>) -> Self {
+        let title = title.into();
         let chosen_idx = Arc::new(Mutex::new(0));
         let chosen_idx_mem = chosen_idx.clone();
         let mut state: ListState = ListState::default();
@@ -103,9 +325,7 @@ impl<'a> EnumInput<'a> {
                                 return ChoiceResult::Remove;
                             }
                             KeyCode::Up => {
-                                if selection_idx > 0 {
-                                    selection_idx -= 1;
-                                }
+                                selection_idx = selection_idx.saturating_sub(1);
                             }
                             KeyCode::Down => {
                                 selection_idx += 1;
@@ -125,7 +345,7 @@ impl<'a> EnumInput<'a> {
                     .block(
                         Block::default()
                             .borders(Borders::ALL)
-                            .title(title)
+                            .title(title.clone())
                             .border_style(Style::default().fg(Color::LightGreen)),
                     )
                     .highlight_style(Style::default().fg(Color::Yellow))
@@ -149,3 +369,91 @@ impl<'a> EnumInput<'a> {
         self.chosen_idx.clone()
     }
 }
+
+impl<'a> ReviewInput<'a> {
+    pub fn new(lines: Vec<Line<'static>>, title: impl Into<String>) -> Self {
+        let title = title.into();
+        let accepted = Arc::new(Mutex::new(false));
+        let accepted_mem = accepted.clone();
+        let mut scroll: usize = 0;
+        let mut continue_selected = true;
+        let render_func = move |frame: &mut Frame<tui::Backend>,
+                                area: Rect,
+                                queue: &Option<Vec<KeyEvent>>| {
+            let height = area.height.saturating_sub(3) as usize;
+            let max_scroll = lines.len().saturating_sub(height);
+            if let Some(queue) = queue {
+                for key in queue {
+                    match key.code {
+                        KeyCode::Enter => {
+                            *accepted.lock().unwrap() = continue_selected;
+                            return ChoiceResult::Remove;
+                        }
+                        KeyCode::Up => scroll = scroll.saturating_sub(1),
+                        KeyCode::Down => scroll = (scroll + 1).min(max_scroll),
+                        KeyCode::PageUp => scroll = scroll.saturating_sub(height.max(1)),
+                        KeyCode::PageDown => scroll = (scroll + height.max(1)).min(max_scroll),
+                        KeyCode::Home => scroll = 0,
+                        KeyCode::End => scroll = max_scroll,
+                        KeyCode::Left | KeyCode::Right | KeyCode::Tab => {
+                            continue_selected = !continue_selected
+                        }
+                        _ => {}
+                    }
+                }
+            }
+
+            let block = Block::default()
+                .borders(Borders::ALL)
+                .title(title.clone())
+                .border_style(Style::default().fg(Color::LightGreen));
+            let inner = block.inner(area);
+            frame.render_widget(block, area);
+            let chunks = Layout::default()
+                .direction(Direction::Vertical)
+                .constraints([Constraint::Min(0), Constraint::Length(1)])
+                .split(inner);
+
+            let items: Vec<ListItem> = lines
+                .iter()
+                .skip(scroll)
+                .take(height)
+                .map(|line| ListItem::new(line.clone()))
+                .collect();
+            frame.render_widget(List::new(items), chunks[0]);
+
+            let button = |label: &'static str, selected: bool| match selected {
+                true => Span::styled(format!("> {} ", label), Style::default().fg(Color::Yellow)),
+                false => Span::raw(format!("  {} ", label)),
+            };
+            let buttons = Line::from(vec![
+                button("Continue", continue_selected),
+                button("Abort", !continue_selected),
+                Span::styled(
+                    format!(
+                        "  Up/Down/PgUp/PgDn: scroll ({}/{})",
+                        (scroll + height).min(lines.len()),
+                        lines.len()
+                    ),
+                    Style::default().fg(Color::DarkGray),
+                ),
+            ]);
+            frame.render_widget(Paragraph::new(buttons), chunks[1]);
+            ChoiceResult::Continue
+        };
+
+        ReviewInput {
+            choice: Some(Choice::new(render_func).wide()),
+            accepted: accepted_mem,
+        }
+    }
+
+    pub fn choice(&mut self) -> Choice<'a> {
+        self.choice.take().expect("Choice can only be called once!")
+    }
+
+    // true if the user chose to continue
+    pub fn output(&self) -> Arc<Mutex<bool>> {
+        self.accepted.clone()
+    }
+}
@@ -1,10 +1,8 @@
 use anyhow::{anyhow, Result};
 
 use std::{
-    env,
-    fs::{self, File, Permissions},
+    fs::{self, File},
     io::Write,
-    os::unix::prelude::PermissionsExt,
     path::Path,
     sync::{Arc, Mutex},
     time::Instant,
@@ -18,7 +16,7 @@ pub fn download(url: String, output: String, ui: Arc<Mutex<UI>>) -> Result<()> {
     ui.lock()
         .unwrap()
         .log()
-        .append(format!("Starting to download {}.", url));
+        .info(format!("Starting to download {}.", url));
 
     let mut curl = Curl::new();
     curl.url(url.clone().as_str())?;
@@ -49,7 +47,7 @@ pub fn download(url: String, output: String, ui: Arc<Mutex<UI>>) -> Result<()> {
             last_progress_update = Instant::now();
 
             let percent = (downloaded as f32 / total as f32) * 100.0;
-            progress_ui.lock().unwrap().log().append(format!(
+            progress_ui.lock().unwrap().log().info(format!(
                 "Downloaded {:.2} MiB/{:.2} MiB, {:.2}%",
                 downloaded / (1024.0 * 1024.0),
                 total / (1024.0 * 1024.0),
@@ -73,7 +71,7 @@ pub fn download(url: String, output: String, ui: Arc<Mutex<UI>>) -> Result<()> {
     ui.lock()
         .unwrap()
         .log()
-        .append(format!("Finished downloading {}.", url));
+        .info(format!("Finished downloading {}.", url));
 
     Ok(())
 }
@@ -0,0 +1,240 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    ffi::OsStr,
+    fs,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use anyhow::{anyhow, Result};
+
+// Symbol (without CONFIG_) -> value. "# CONFIG_FOO is not set" is stored as "n"
+pub type Config = BTreeMap<String, String>;
+
+pub fn parse(text: &str) -> Config {
+    let mut config = Config::new();
+    for line in text.lines() {
+        let line = line.trim();
+        if let Some(symbol) = line
+            .strip_prefix("# CONFIG_")
+            .and_then(|line| line.strip_suffix(" is not set"))
+        {
+            config.insert(String::from(symbol), String::from("n"));
+        } else if let Some((symbol, value)) = line
+            .strip_prefix("CONFIG_")
+            .and_then(|line| line.split_once('='))
+        {
+            config.insert(String::from(symbol), String::from(value));
+        }
+    }
+    config
+}
+
+// Inverse of parse
+pub fn to_text(config: &Config) -> String {
+    config
+        .iter()
+        .map(|(symbol, value)| match value.as_str() {
+            "n" => format!("# CONFIG_{} is not set\n", symbol),
+            value => format!("CONFIG_{}={}\n", symbol, value),
+        })
+        .collect()
+}
+
+// Gzipped configs (e.g. /proc/config.gz) are decompressed with zcat
+pub fn read_text<P: AsRef<Path>>(path: P) -> Result<String> {
+    let path = path.as_ref();
+    if path.extension() != Some(OsStr::new("gz")) {
+        return Ok(fs::read_to_string(path)?);
+    }
+    let output = Command::new("zcat").arg(path).output()?;
+    if !output.status.success() {
+        return Err(anyhow!("Failed to decompress {}", path.display()));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+pub fn read<P: AsRef<Path>>(path: P) -> Result<Config> {
+    Ok(parse(&read_text(path)?))
+}
+
+// Fails if path isn't a file with at least one config symbol in it
+pub fn check<P: AsRef<Path>>(path: P) -> Result<()> {
+    let path = path.as_ref();
+    if !path.is_file() {
+        return Err(anyhow!("{} is not a file", path.display()));
+    }
+    if read(path)?.is_empty() {
+        return Err(anyhow!("{} is not a kernel config", path.display()));
+    }
+    Ok(())
+}
+
+// Symbols that aren't set count as "n"
+#[derive(Default)]
+pub struct Diff {
+    // Symbol, new value
+    pub added: Vec<(String, String)>,
+    // Symbol, old value
+    pub removed: Vec<(String, String)>,
+    // Symbol, old value, new value
+    pub changed: Vec<(String, String, String)>,
+}
+
+impl Diff {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.changed.is_empty()
+    }
+
+    // "+3 -1 ~2"
+    pub fn summary(&self) -> String {
+        format!(
+            "+{} -{} ~{}",
+            self.added.len(),
+            self.removed.len(),
+            self.changed.len()
+        )
+    }
+
+    // One line per symbol, like scripts/diffconfig: "-FOO y", "+BAR m", " BAZ y -> m"
+    pub fn lines(&self) -> Vec<String> {
+        let removed = self
+            .removed
+            .iter()
+            .map(|(symbol, old)| format!("-{} {}", symbol, old));
+        let added = self
+            .added
+            .iter()
+            .map(|(symbol, new)| format!("+{} {}", symbol, new));
+        let changed = self
+            .changed
+            .iter()
+            .map(|(symbol, old, new)| format!(" {} {} -> {}", symbol, old, new));
+        removed.chain(added).chain(changed).collect()
+    }
+}
+
+pub fn diff(old: &Config, new: &Config) -> Diff {
+    let mut diff = Diff::default();
+    let symbols: BTreeSet<&String> = old.keys().chain(new.keys()).collect();
+    for symbol in symbols {
+        let old = old.get(symbol).map(String::as_str).unwrap_or("n");
+        let new = new.get(symbol).map(String::as_str).unwrap_or("n");
+        if old == new {
+            continue;
+        }
+        let symbol = symbol.clone();
+        if old == "n" {
+            diff.added.push((symbol, String::from(new)));
+        } else if new == "n" {
+            diff.removed.push((symbol, String::from(old)));
+        } else {
+            diff.changed
+                .push((symbol, String::from(old), String::from(new)));
+        }
+    }
+    diff
+}
+
+pub fn is_enabled(config: &Config, symbol: &str) -> bool {
+    matches!(
+        config.get(symbol).map(String::as_str),
+        Some("y") | Some("m")
+    )
+}
+
+// Where the config of the running kernel may be. /proc/config.gz needs IKCONFIG_PROC
+pub fn running_config() -> Option<PathBuf> {
+    let output = Command::new("uname").arg("-r").output().ok()?;
+    let release = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    [
+        PathBuf::from("/proc/config.gz"),
+        PathBuf::from(format!("/boot/config-{}", release)),
+        PathBuf::from(format!("/usr/lib/modules/{}/config", release)),
+    ]
+    .into_iter()
+    .find(|path| path.is_file())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TEXT: &str = "\
+#
+# Automatically generated file; DO NOT EDIT.
+#
+CONFIG_A=y
+CONFIG_B=m
+# CONFIG_C is not set
+CONFIG_D=\"some string\"
+CONFIG_E=0x10
+# Just a comment
+";
+
+    #[test]
+    fn parse_values() {
+        let config = parse(TEXT);
+        assert_eq!(config.len(), 5);
+        assert_eq!(config["A"], "y");
+        assert_eq!(config["B"], "m");
+        assert_eq!(config["D"], "\"some string\"");
+        assert_eq!(config["E"], "0x10");
+    }
+
+    #[test]
+    fn not_set_is_not_absent() {
+        let config = parse(TEXT);
+        assert_eq!(config.get("C").map(String::as_str), Some("n"));
+        assert_eq!(config.get("F"), None);
+        assert!(!is_enabled(&config, "C"));
+        assert!(!is_enabled(&config, "F"));
+        assert!(is_enabled(&config, "B"));
+    }
+
+    #[test]
+    fn to_text_round_trip() {
+        let config = parse(TEXT);
+        let text = to_text(&config);
+        assert!(text.contains("# CONFIG_C is not set\n"));
+        assert!(text.contains("CONFIG_D=\"some string\"\n"));
+        assert_eq!(parse(&text), config);
+    }
+
+    #[test]
+    fn diff_configs() {
+        let old = parse("CONFIG_A=y\nCONFIG_B=m\nCONFIG_C=y\n# CONFIG_D is not set\nCONFIG_E=y");
+        let new = parse("CONFIG_A=y\nCONFIG_B=y\n# CONFIG_C is not set\nCONFIG_D=m\nCONFIG_F=y");
+        let diff = diff(&old, &new);
+        assert_eq!(
+            diff.added,
+            vec![
+                (String::from("D"), String::from("m")),
+                (String::from("F"), String::from("y"))
+            ]
+        );
+        assert_eq!(
+            diff.removed,
+            vec![
+                (String::from("C"), String::from("y")),
+                (String::from("E"), String::from("y"))
+            ]
+        );
+        assert_eq!(
+            diff.changed,
+            vec![(String::from("B"), String::from("m"), String::from("y"))]
+        );
+        assert_eq!(diff.summary(), "+2 -2 ~1");
+        assert_eq!(
+            diff.lines(),
+            vec!["-C y", "-E y", "+D m", "+F y", " B m -> y"]
+        );
+    }
+
+    #[test]
+    fn missing_is_the_same_as_not_set() {
+        let diff = diff(&parse("# CONFIG_A is not set"), &parse(""));
+        assert!(diff.is_empty());
+        assert_eq!(diff.summary(), "+0 -0 ~0");
+    }
+}
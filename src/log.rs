@@ -1,48 +1,682 @@
+use std::{
+    collections::VecDeque,
+    env,
+    ffi::CString,
+    fs::{self, File},
+    io::Write,
+    mem::MaybeUninit,
+    sync::Arc,
+};
+
+use anyhow::Result;
+
+use crossterm::event::{KeyCode, KeyEvent};
 use ratatui::{
-    prelude::{Corner, Rect},
-    widgets::{Block, Borders, List, ListItem},
+    prelude::{Constraint, Corner, Direction, Layout, Rect},
+    style::{Color, Modifier, Style},
+    text::{Line, Span},
+    widgets::{Block, Borders, List, ListItem, Paragraph},
     Frame,
 };
 
 use crate::tui;
 
+// Which output stream of a process a line was written to
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Stream {
+    Stdout,
+    Stderr,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Level {
+    // The commands we run and their results
+    Debug,
+    Info,
+    Warning,
+    Error,
+}
+
+impl Level {
+    fn name(&self) -> &'static str {
+        match self {
+            Level::Debug => "DEBUG",
+            Level::Info => "INFO",
+            Level::Warning => "WARNING",
+            Level::Error => "ERROR",
+        }
+    }
+
+    // Guesses the level of process output
+    fn classify(text: &str) -> Level {
+        let text = text.to_lowercase();
+        if text.contains("error:") || text.contains("error ") {
+            Level::Error
+        } else if text.contains("warning:") || text.contains("warning ") {
+            Level::Warning
+        } else {
+            Level::Info
+        }
+    }
+}
+
+// Where a record came from
+#[derive(Clone)]
+pub enum Source {
+    Rkbuild,
+    // The program (e.g. make) and the stream it wrote to
+    Process(Arc<str>, Stream),
+}
+
+impl Source {
+    fn stream(&self) -> Option<Stream> {
+        match self {
+            Source::Rkbuild => None,
+            Source::Process(_, stream) => Some(*stream),
+        }
+    }
+
+    fn name(&self) -> &str {
+        match self {
+            Source::Rkbuild => "rkbuild",
+            Source::Process(program, _) => program,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Filter {
+    All,
+    Errors,
+    Warnings,
+    // Only lines of the newest stage
+    Stage,
+}
+
+struct Record<'a> {
+    // Increases with every record, so records can be referenced even after old ones were dropped
+    id: u64,
+    level: Level,
+    time: libc::time_t,
+    stage: usize,
+    source: Source,
+    line: Line<'a>,
+    // The line without styling, for searching and filtering
+    text: String,
+}
+
+impl<'a> Record<'a> {
+    fn style(&self) -> Style {
+        match (self.level, self.source.stream()) {
+            (Level::Error, _) => Style::default().fg(Color::Red).add_modifier(Modifier::BOLD),
+            (Level::Warning, _) => Style::default().fg(Color::Yellow),
+            (Level::Debug, _) => Style::default().fg(Color::DarkGray),
+            (Level::Info, Some(Stream::Stderr)) => Style::default().fg(Color::LightRed),
+            (Level::Info, _) => Style::default(),
+        }
+    }
+}
+
+fn plain_text(line: &Line) -> String {
+    line.spans
+        .iter()
+        .map(|span| span.content.as_ref())
+        .collect()
+}
+
+// Keep this many lines by default. Can be overwritten with RKBUILD_LOG_LINES
+const DEFAULT_MAX_LINES: usize = 200_000;
+
+const LOG_DIR: &str = "/var/log/rkbuild";
+// Keep the logs of this many runs. Can be overwritten with RKBUILD_LOG_FILES
+const DEFAULT_KEPT_LOGS: usize = 10;
+
+fn now() -> libc::time_t {
+    unsafe { libc::time(std::ptr::null_mut()) }
+}
+
+// Formats the current local time with strftime
+pub fn timestamp(format: &str) -> String {
+    format_time(now(), format)
+}
+
+fn format_time(time: libc::time_t, format: &str) -> String {
+    let format = CString::new(format).unwrap();
+    let mut buf = [0u8; 64];
+    let len = unsafe {
+        let mut tm = MaybeUninit::<libc::tm>::uninit();
+        libc::localtime_r(&time, tm.as_mut_ptr());
+        libc::strftime(
+            buf.as_mut_ptr() as *mut libc::c_char,
+            buf.len(),
+            format.as_ptr(),
+            tm.as_ptr(),
+        )
+    };
+    String::from_utf8_lossy(&buf[..len]).into_owned()
+}
+
+struct LogFile {
+    file: File,
+    // Id of the first line, that isn't written yet
+    unwritten: u64,
+}
+
 pub struct Log<'a> {
-    log_items: Vec<ListItem<'a>>,
+    log_items: VecDeque<Record<'a>>,
+    max_lines: usize,
+    next_id: u64,
+    // Index into stages of the current stage
+    stage: usize,
+    stages: Vec<String>,
+
+    // Ids of the lines that pass the filter
+    view: VecDeque<u64>,
+    filter: Filter,
+
+    // How many lines we are scrolled up from the newest line of the view
+    scroll: usize,
+    // Jump to new lines as they arrive
+    follow: bool,
+    // Number of visible lines in the last draw
+    page_height: usize,
+    // The log gets all keys, even if a prompt is active
+    focused: bool,
+    show_time: bool,
+
+    search: String,
+    // The search is being typed
+    editing_search: bool,
+    // Id of the line with the current match
+    search_match: Option<u64>,
+
+    file: Option<LogFile>,
+    // Id of the first line of the current build
+    run_start: u64,
+    run_timestamp: String,
+}
+
+impl<'a> Default for Log<'a> {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl<'a> Log<'a> {
     pub fn new() -> Self {
         Log {
-            log_items: Vec::new(),
+            log_items: VecDeque::new(),
+            max_lines: env::var("RKBUILD_LOG_LINES")
+                .ok()
+                .and_then(|lines| lines.parse().ok())
+                .unwrap_or(DEFAULT_MAX_LINES),
+            next_id: 0,
+            stage: 0,
+            stages: vec![String::new()],
+            view: VecDeque::new(),
+            filter: Filter::All,
+            scroll: 0,
+            follow: true,
+            page_height: 0,
+            focused: false,
+            show_time: false,
+            search: String::new(),
+            editing_search: false,
+            search_match: None,
+            file: None,
+            run_start: 0,
+            run_timestamp: timestamp("%Y-%m-%d_%H-%M-%S"),
+        }
+    }
+
+    // Marks the start of a new build, which gets its own log file
+    pub fn begin_run(&mut self) {
+        self.write_pending(self.next_id);
+        self.file = None;
+        self.run_start = self.next_id;
+        self.run_timestamp = timestamp("%Y-%m-%d_%H-%M-%S");
+    }
+
+    // Writes the current run (and everything after) to /var/log/rkbuild. Returns the path
+    pub fn persist(&mut self, postfix: &str) -> Result<String> {
+        fs::create_dir_all(LOG_DIR)?;
+        let kept_logs = env::var("RKBUILD_LOG_FILES")
+            .ok()
+            .and_then(|files| files.parse().ok())
+            .unwrap_or(DEFAULT_KEPT_LOGS);
+
+        // The names start with the date, so sorting them sorts by age
+        let mut logs: Vec<_> = fs::read_dir(LOG_DIR)?
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.path())
+            .filter(|path| path.extension().map(|ext| ext == "log").unwrap_or(false))
+            .collect();
+        logs.sort();
+        // Make room for the new one
+        let remove = (logs.len() + 1).saturating_sub(kept_logs);
+        for old_log in logs.iter().take(remove) {
+            fs::remove_file(old_log)?;
         }
+
+        let path = format!("{}/{}-{}.log", LOG_DIR, self.run_timestamp, postfix);
+        self.file = Some(LogFile {
+            file: File::create(&path)?,
+            unwritten: self.run_start,
+        });
+        self.write_pending(self.next_id);
+        Ok(path)
+    }
+
+    // Writes lines that aren't in the log file yet, up to (excluding) the given id
+    fn write_pending(&mut self, up_to: u64) {
+        let Some(log_file) = &mut self.file else {
+            return;
+        };
+        let first = self.log_items.front().map(|item| item.id).unwrap_or(0);
+        let mut text = String::new();
+        for id in log_file.unwritten.max(first)..up_to {
+            if let Some(item) = self.log_items.get((id - first) as usize) {
+                text.push_str(&format!(
+                    "{} {:<7} [{}] {}: {}\n",
+                    format_time(item.time, "%Y-%m-%d %H:%M:%S"),
+                    item.level.name(),
+                    self.stages[item.stage],
+                    item.source.name(),
+                    item.text
+                ));
+            }
+        }
+        log_file.unwritten = log_file.unwritten.max(up_to);
+        if text.is_empty() {
+            return;
+        }
+        if let Some(err) = log_file.file.write_all(text.as_bytes()).err() {
+            self.file = None;
+            self.error(format!("Failed to write log file: {}", err));
+        }
+    }
+
+    fn push(&mut self, level: Level, source: Source, line: Line<'a>) {
+        if self.log_items.len() >= self.max_lines {
+            if let Some(item) = self.log_items.pop_front() {
+                if self.view.front() == Some(&item.id) {
+                    self.view.pop_front();
+                }
+            }
+        }
+        let item = Record {
+            id: self.next_id,
+            level,
+            time: now(),
+            stage: self.stage,
+            source,
+            text: plain_text(&line),
+            line,
+        };
+        self.next_id += 1;
+
+        if self.passes_filter(&item) {
+            self.view.push_back(item.id);
+            if !self.follow {
+                // Keep the view where it is
+                self.scroll += 1;
+            }
+        }
+        self.log_items.push_back(item);
+
+        // The newest line may still change (progress output), so it gets written later
+        self.write_pending(self.next_id - 1);
+    }
+
+    fn message(&mut self, level: Level, text: String) {
+        self.push(level, Source::Rkbuild, Line::from(text));
+        // Our own messages are final
+        self.write_pending(self.next_id);
     }
 
-    pub fn append(&mut self, item: String) {
-        self.log_items.push(ListItem::new(item));
+    pub fn debug(&mut self, text: impl Into<String>) {
+        self.message(Level::Debug, text.into());
+    }
+
+    pub fn info(&mut self, text: impl Into<String>) {
+        self.message(Level::Info, text.into());
+    }
+
+    pub fn warn(&mut self, text: impl Into<String>) {
+        self.message(Level::Warning, text.into());
+    }
+
+    pub fn error(&mut self, text: impl Into<String>) {
+        self.message(Level::Error, text.into());
+    }
+
+    // Output of a process
+    pub fn output(&mut self, line: Line<'a>, program: &Arc<str>, stream: Stream) {
+        let level = Level::classify(&plain_text(&line));
+        self.push(level, Source::Process(program.clone(), stream), line);
+    }
+
+    // Starts a new section of the log, which can be filtered for
+    pub fn begin_stage(&mut self, name: &str) {
+        self.stages.push(String::from(name));
+        self.stage = self.stages.len() - 1;
+        // The lines of the previous stage don't belong to the current one anymore
+        if self.filter == Filter::Stage {
+            self.rebuild_view();
+        }
+        self.info(name);
     }
 
     pub fn clean(&mut self) {
         self.log_items.clear();
+        self.view.clear();
+        self.scroll = 0;
+        self.search_match = None;
     }
 
-    pub fn replace_newest(&mut self, item: String) {
-        self.log_items.pop();
-        self.log_items.push(ListItem::new(item));
+    // Replaces the newest output of the stream (e.g. for progress bars), since the other stream
+    // may have written something in between
+    pub fn replace_output(&mut self, line: Line<'a>, program: &Arc<str>, stream: Stream) {
+        match self
+            .log_items
+            .iter_mut()
+            .rev()
+            .find(|item| item.source.stream() == Some(stream))
+        {
+            Some(item) => {
+                item.text = plain_text(&line);
+                item.level = Level::classify(&item.text);
+                item.line = line;
+            }
+            None => self.output(line, program, stream),
+        }
     }
 
-    pub fn draw(&mut self, frame: &mut Frame<tui::Backend>, draw_area: Rect) {
-        // Trim list
-        // The border takes two rows, so we have place for draw_area.height - 2 elements
-        let num_elements = draw_area.height as usize - 2;
-        if num_elements < self.log_items.len() {
-            // Split off n elements from the front
-            let split_idx = self.log_items.len() - num_elements;
-            self.log_items = self.log_items.split_off(split_idx);
+    fn passes_filter(&self, item: &Record) -> bool {
+        match self.filter {
+            Filter::All => true,
+            Filter::Errors => item.level == Level::Error,
+            Filter::Warnings => item.level == Level::Warning,
+            Filter::Stage => item.stage == self.stage,
+        }
+    }
+
+    fn set_filter(&mut self, filter: Filter) {
+        self.filter = filter;
+        self.rebuild_view();
+    }
+
+    // Needed whenever the filter changes what it matches
+    fn rebuild_view(&mut self) {
+        self.view = self
+            .log_items
+            .iter()
+            .filter(|item| self.passes_filter(item))
+            .map(|item| item.id)
+            .collect();
+        self.follow = true;
+        self.scroll = 0;
+    }
+
+    fn item(&self, id: u64) -> Option<&Record<'a>> {
+        let first = self.log_items.front()?.id;
+        self.log_items.get(id.checked_sub(first)? as usize)
+    }
+
+    fn max_scroll(&self) -> usize {
+        self.view.len().saturating_sub(self.page_height)
+    }
+
+    // Jumps to the next line in the view containing the search, wrapping around at the end
+    fn find_match(&mut self, forward: bool) {
+        if self.search.is_empty() || self.view.is_empty() {
+            return;
+        }
+        let len = self.view.len();
+        // Start after the current match, or at the top of the screen
+        let start = self
+            .search_match
+            .and_then(|id| self.view.iter().position(|view_id| *view_id == id))
+            .unwrap_or_else(|| {
+                let top = (len - self.scroll.min(len)).saturating_sub(self.page_height);
+                match forward {
+                    // The loop skips start, so begin one line before the top
+                    true => top + len - 1,
+                    false => top,
+                }
+            });
+
+        for offset in 1..=len {
+            let pos = match forward {
+                true => (start + offset) % len,
+                false => (start + len * 2 - offset) % len,
+            };
+            let id = self.view[pos];
+            if self
+                .item(id)
+                .map(|item| item.text.contains(&self.search))
+                .unwrap_or(false)
+            {
+                self.search_match = Some(id);
+                // Put the match at the top of the screen
+                self.follow = false;
+                self.scroll = len.saturating_sub(pos + self.page_height);
+                return;
+            }
+        }
+        self.search_match = None;
+    }
+
+    // Returns whether the key was used. Keys that could mean something for a prompt are only
+    // used, if no prompt is active or the log is focused.
+    pub fn handle_key(&mut self, key: &KeyEvent, prompt_active: bool) -> bool {
+        if self.editing_search {
+            match key.code {
+                KeyCode::Enter => {
+                    self.editing_search = false;
+                    self.search_match = None;
+                    self.find_match(true);
+                }
+                KeyCode::Esc => {
+                    self.editing_search = false;
+                    self.search.clear();
+                }
+                KeyCode::Backspace => {
+                    self.search.pop();
+                }
+                KeyCode::Char(c) => self.search.push(c),
+                _ => {}
+            }
+            return true;
+        }
+
+        let focused = self.focused || !prompt_active;
+        let page = self.page_height.max(1);
+        match key.code {
+            KeyCode::F(2) => self.focused = !self.focused,
+            KeyCode::PageUp => {
+                self.follow = false;
+                self.scroll = (self.scroll + page).min(self.max_scroll());
+            }
+            KeyCode::PageDown => {
+                self.scroll = self.scroll.saturating_sub(page);
+            }
+            KeyCode::Home if focused => {
+                self.follow = false;
+                self.scroll = self.max_scroll();
+            }
+            KeyCode::End if focused => {
+                self.follow = true;
+                self.scroll = 0;
+            }
+            KeyCode::Char('f') if focused => {
+                self.follow = !self.follow;
+                if self.follow {
+                    self.scroll = 0;
+                }
+            }
+            KeyCode::Char('/') if focused => {
+                self.editing_search = true;
+                self.search.clear();
+                self.search_match = None;
+            }
+            KeyCode::Char('n') if focused => self.find_match(true),
+            KeyCode::Char('N') if focused => self.find_match(false),
+            // Esc only quits if there is nothing to cancel
+            KeyCode::Esc if !self.search.is_empty() => {
+                self.search.clear();
+                self.search_match = None;
+            }
+            KeyCode::Char('a') if focused => self.set_filter(Filter::All),
+            KeyCode::Char('e') if focused => self.set_filter(Filter::Errors),
+            KeyCode::Char('w') if focused => self.set_filter(Filter::Warnings),
+            KeyCode::Char('s') if focused => self.set_filter(Filter::Stage),
+            KeyCode::Char('t') if focused => self.show_time = !self.show_time,
+            _ => return false,
+        }
+        true
+    }
+
+    // Splits the spans of the line, so that every occurrence of the search is highlighted
+    fn highlight(&self, item: &Record<'a>) -> Line<'a> {
+        let matches: Vec<(usize, usize)> = item
+            .text
+            .match_indices(&self.search)
+            .map(|(start, found)| (start, start + found.len()))
+            .collect();
+        if self.search.is_empty() || matches.is_empty() {
+            return item.line.clone();
+        }
+        let highlight = match self.search_match == Some(item.id) {
+            true => Style::default().fg(Color::Black).bg(Color::LightYellow),
+            false => Style::default().fg(Color::Black).bg(Color::Yellow),
+        };
+
+        let mut spans = Vec::new();
+        // Byte offset of the current span in item.text
+        let mut offset = 0;
+        for span in &item.line.spans {
+            let content = span.content.as_ref();
+            let mut pos = 0;
+            while pos < content.len() {
+                let abs = offset + pos;
+                match matches
+                    .iter()
+                    .find(|(start, end)| *start <= abs && abs < *end)
+                {
+                    Some((_, end)) => {
+                        let end = (end - offset).min(content.len());
+                        spans.push(Span::styled(
+                            content[pos..end].to_string(),
+                            span.style.patch(highlight),
+                        ));
+                        pos = end;
+                    }
+                    None => {
+                        let end = matches
+                            .iter()
+                            .map(|(start, _)| *start)
+                            .find(|start| *start > abs)
+                            .map(|start| start - offset)
+                            .unwrap_or(content.len())
+                            .min(content.len());
+                        spans.push(Span::styled(content[pos..end].to_string(), span.style));
+                        pos = end;
+                    }
+                }
+            }
+            offset += content.len();
         }
+        Line::from(spans)
+    }
+
+    pub fn draw(&mut self, frame: &mut Frame<tui::Backend>, draw_area: Rect) {
+        let show_search = self.editing_search || !self.search.is_empty();
+        let chunks = Layout::default()
+            .direction(Direction::Vertical)
+            .constraints([
+                Constraint::Min(3),
+                Constraint::Length(match show_search {
+                    true => 1,
+                    false => 0,
+                }),
+            ])
+            .split(draw_area);
+        let list_area = chunks[0];
 
-        let list_widget = List::new(self.log_items.clone())
-            .block(Block::default().borders(Borders::ALL).title("Log"))
+        // The border takes two rows, so we have place for list_area.height - 2 elements
+        self.page_height = (list_area.height as usize).saturating_sub(2);
+        self.scroll = self.scroll.min(self.max_scroll());
+        let end = self.view.len() - self.scroll;
+        let start = end.saturating_sub(self.page_height);
+
+        let items: Vec<ListItem> = self
+            .view
+            .range(start..end)
+            .filter_map(|id| self.item(*id))
+            .map(|item| {
+                let mut line = self.highlight(item);
+                if self.show_time {
+                    line.spans.insert(
+                        0,
+                        Span::styled(
+                            format_time(item.time, "%H:%M:%S "),
+                            Style::default().fg(Color::DarkGray),
+                        ),
+                    );
+                }
+                // Explicit colors of the process (e.g. gcc) still take precedence
+                ListItem::new(line).style(item.style())
+            })
+            .collect();
+        let title = format!(
+            "Log [{}/{}]{}{}{}",
+            end,
+            self.view.len(),
+            match self.filter {
+                Filter::All => "",
+                Filter::Errors => " (errors)",
+                Filter::Warnings => " (warnings)",
+                Filter::Stage => " (current stage)",
+            },
+            match self.follow {
+                true => "",
+                false => " (paused, End to follow)",
+            },
+            match self.focused {
+                true => " (F2 to return to prompt)",
+                false => "",
+            }
+        );
+        let border_style = match self.focused {
+            true => Style::default().fg(Color::LightGreen),
+            false => Style::default(),
+        };
+        let list_widget = List::new(items)
+            .block(
+                Block::default()
+                    .borders(Borders::ALL)
+                    .title(title)
+                    .border_style(border_style),
+            )
             .start_corner(Corner::TopLeft);
-        frame.render_widget(list_widget, draw_area);
+        frame.render_widget(list_widget, list_area);
+
+        if show_search {
+            let search_area = chunks[1];
+            let status = match (self.editing_search, self.search_match) {
+                (true, _) => "",
+                (false, Some(_)) => "  (n/N for next/previous)",
+                (false, None) => "  (not found)",
+            };
+            let search = Paragraph::new(format!("/{}{}", self.search, status));
+            frame.render_widget(search, search_area);
+            if self.editing_search {
+                frame.set_cursor(
+                    search_area.x + 1 + self.search.chars().count() as u16,
+                    search_area.y,
+                );
+            }
+        }
     }
 }
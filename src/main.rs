@@ -1,8 +1,14 @@
+pub mod ansi;
 pub mod build;
 pub mod choices;
 pub mod download;
+pub mod kconfig;
 pub mod log;
+pub mod pipeline;
 pub mod process;
+pub mod profile;
+pub mod progress;
+pub mod state;
 pub mod tui;
 pub mod ui;
 pub mod user;
@@ -42,6 +48,17 @@ fn main() {
             Ok(TickResult::Exit) => {
                 break;
             }
+            Ok(TickResult::Suspend(request)) => {
+                if let Some(err) = tui.suspend().err() {
+                    println!("Err: {}", err);
+                    break;
+                }
+                request.hand_over();
+                if let Some(err) = tui.resume().err() {
+                    println!("Err: {}", err);
+                    break;
+                }
+            }
             Ok(_) => {}
             Err(err) => {
                 println!("Err: {}", err);
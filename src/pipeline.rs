@@ -0,0 +1,142 @@
+use std::time::{Duration, Instant};
+
+use ratatui::{
+    prelude::Rect,
+    style::{Color, Style},
+    text::{Line, Span},
+    widgets::{Block, Borders, List, ListItem},
+    Frame,
+};
+
+use crate::tui;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Stage {
+    Download,
+    Extract,
+    Configure,
+    Compile,
+    Install,
+    Dkms,
+    Initramfs,
+    Clean,
+}
+
+impl Stage {
+    pub const ALL: [Stage; 8] = [
+        Stage::Download,
+        Stage::Extract,
+        Stage::Configure,
+        Stage::Compile,
+        Stage::Install,
+        Stage::Dkms,
+        Stage::Initramfs,
+        Stage::Clean,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Stage::Download => "Download",
+            Stage::Extract => "Extract",
+            Stage::Configure => "Configure",
+            Stage::Compile => "Compile",
+            Stage::Install => "Install",
+            Stage::Dkms => "DKMS",
+            Stage::Initramfs => "Initramfs",
+            Stage::Clean => "Clean",
+        }
+    }
+
+    fn idx(&self) -> usize {
+        Stage::ALL.iter().position(|stage| stage == self).unwrap()
+    }
+}
+
+#[derive(Clone, Copy)]
+enum State {
+    Pending,
+    Running(Instant),
+    Done(Duration),
+    Failed(Duration),
+    // Not needed for this build (e.g. extracting a git checkout)
+    Skipped,
+}
+
+pub struct Pipeline {
+    states: [State; Stage::ALL.len()],
+}
+
+impl Default for Pipeline {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Pipeline {
+    pub fn new() -> Self {
+        Pipeline {
+            states: [State::Pending; Stage::ALL.len()],
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.states = [State::Pending; Stage::ALL.len()];
+    }
+
+    pub fn start(&mut self, stage: Stage) {
+        self.states[stage.idx()] = State::Running(Instant::now());
+    }
+
+    pub fn finish(&mut self, stage: Stage, success: bool) {
+        let elapsed = match self.states[stage.idx()] {
+            State::Running(start) => start.elapsed(),
+            _ => Duration::ZERO,
+        };
+        self.states[stage.idx()] = match success {
+            true => State::Done(elapsed),
+            false => State::Failed(elapsed),
+        };
+    }
+
+    pub fn skip(&mut self, stage: Stage) {
+        self.states[stage.idx()] = State::Skipped;
+    }
+
+    pub fn draw(&self, frame: &mut Frame<tui::Backend>, area: Rect) {
+        let items: Vec<ListItem> = Stage::ALL
+            .iter()
+            .zip(self.states)
+            .map(|(stage, state)| {
+                let (symbol, elapsed, style) = match state {
+                    State::Pending => (" ", None, Style::default().fg(Color::DarkGray)),
+                    State::Running(start) => (
+                        ">",
+                        Some(start.elapsed()),
+                        Style::default().fg(Color::Yellow),
+                    ),
+                    State::Done(elapsed) => {
+                        ("✓", Some(elapsed), Style::default().fg(Color::LightGreen))
+                    }
+                    State::Failed(elapsed) => {
+                        ("✗", Some(elapsed), Style::default().fg(Color::LightRed))
+                    }
+                    State::Skipped => ("-", None, Style::default().fg(Color::DarkGray)),
+                };
+                let elapsed = match elapsed {
+                    Some(elapsed) => format!(
+                        "{:02}:{:02}",
+                        elapsed.as_secs() / 60,
+                        elapsed.as_secs() % 60
+                    ),
+                    None => String::new(),
+                };
+                ListItem::new(Line::from(vec![
+                    Span::styled(format!("{} {:<10}", symbol, stage.name()), style),
+                    Span::raw(elapsed),
+                ]))
+            })
+            .collect();
+        let list = List::new(items).block(Block::default().borders(Borders::ALL).title("Pipeline"));
+        frame.render_widget(list, area);
+    }
+}
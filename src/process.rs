@@ -1,61 +1,297 @@
 use anyhow::{anyhow, Result};
-use libc;
 use std::{
     env,
-    io::Read,
-    os,
-    process::{Command, Stdio},
+    fs::File,
+    io::{self, Read},
+    mem::MaybeUninit,
+    os::{
+        fd::{AsRawFd, FromRawFd},
+        unix::process::CommandExt,
+    },
+    process::{Command, ExitStatus, Stdio},
+    ptr,
     sync::{Arc, Mutex},
+    time::Instant,
 };
 
-use crate::ui::UI;
+use crate::{ansi, log::Stream, ui::UI};
 
 enum User {
     Root,
     User,
 }
 
+// Opens a pseudo-terminal, so children think they are writing to a terminal and keep colors
+// and progress output enabled. Returns (master, slave)
+fn open_pty() -> Result<(File, File)> {
+    // The log panel takes up the right three quarters of the screen (minus the border)
+    let (width, height) = crossterm::terminal::size().unwrap_or((160, 50));
+    let size = libc::winsize {
+        ws_row: height.saturating_sub(2).max(1),
+        ws_col: (width - width / 4).saturating_sub(2).max(1),
+        ws_xpixel: 0,
+        ws_ypixel: 0,
+    };
+
+    let mut master = 0;
+    let mut slave = 0;
+    if unsafe { libc::openpty(&mut master, &mut slave, ptr::null_mut(), ptr::null(), &size) } != 0 {
+        return Err(anyhow!(
+            "Failed to open pty: {}",
+            io::Error::last_os_error()
+        ));
+    }
+    let (master, slave) = unsafe { (File::from_raw_fd(master), File::from_raw_fd(slave)) };
+
+    unsafe {
+        // Don't leak the pty into unrelated children
+        libc::fcntl(master.as_raw_fd(), libc::F_SETFD, libc::FD_CLOEXEC);
+        libc::fcntl(slave.as_raw_fd(), libc::F_SETFD, libc::FD_CLOEXEC);
+
+        // Don't translate \n into \r\n, a lone \r is a progress update for us
+        let mut termios = MaybeUninit::<libc::termios>::uninit();
+        if libc::tcgetattr(slave.as_raw_fd(), termios.as_mut_ptr()) == 0 {
+            let mut termios = termios.assume_init();
+            termios.c_oflag &= !libc::ONLCR;
+            libc::tcsetattr(slave.as_raw_fd(), libc::TCSANOW, &termios);
+        }
+    }
+    Ok((master, slave))
+}
+
+#[derive(Debug, PartialEq)]
+enum LineEvent {
+    Append(Stream, String),
+    // Progress output (e.g. "50%\r"), which overwrites the newest line of the stream
+    ReplaceNewest(Stream, String),
+}
+
+// Splits raw process output into lines. Bytes are only decoded once a line is complete, so
+// multibyte characters that are split across reads are decoded correctly.
+struct LineSplitter {
+    stream: Stream,
+    line: Vec<u8>,
+    // The newest log line is an earlier version of the line we are receiving right now
+    overwrite: bool,
+    // The last chunk ended in \r, we don't know yet whether a \n follows
+    pending_cr: bool,
+}
+
+impl LineSplitter {
+    fn new(stream: Stream) -> Self {
+        LineSplitter {
+            stream,
+            line: Vec::new(),
+            overwrite: false,
+            pending_cr: false,
+        }
+    }
+
+    fn feed(&mut self, data: &[u8], events: &mut Vec<LineEvent>) {
+        for &byte in data {
+            if self.pending_cr {
+                self.pending_cr = false;
+                if byte == b'\n' {
+                    // \r\n is just a newline
+                    self.end_line(events);
+                    continue;
+                }
+                self.carriage_return(events);
+            }
+            match byte {
+                b'\n' => self.end_line(events),
+                b'\r' => self.pending_cr = true,
+                _ => self.line.push(byte),
+            }
+        }
+    }
+
+    fn finish(&mut self, events: &mut Vec<LineEvent>) {
+        if self.pending_cr {
+            self.pending_cr = false;
+            self.carriage_return(events);
+        }
+        if !self.line.is_empty() {
+            self.end_line(events);
+        }
+    }
+
+    fn end_line(&mut self, events: &mut Vec<LineEvent>) {
+        events.push(self.take_line());
+        self.overwrite = false;
+    }
+
+    fn carriage_return(&mut self, events: &mut Vec<LineEvent>) {
+        if self.line.is_empty() {
+            return;
+        }
+        events.push(self.take_line());
+        self.overwrite = true;
+    }
+
+    fn take_line(&mut self) -> LineEvent {
+        let line = String::from_utf8_lossy(&self.line).into_owned();
+        self.line.clear();
+        match self.overwrite {
+            true => LineEvent::ReplaceNewest(self.stream, line),
+            false => LineEvent::Append(self.stream, line),
+        }
+    }
+}
+
+// Hands a batch of lines to the log, so we only take the lock once per read
+fn flush_events(events: &mut Vec<LineEvent>, program: &Arc<str>, ui: &Arc<Mutex<UI>>) {
+    if events.is_empty() {
+        return;
+    }
+    let mut ui = ui.lock().unwrap();
+    for event in events.drain(..) {
+        match event {
+            LineEvent::Append(stream, line) => {
+                if let Some(progress) = ui.progress() {
+                    progress.observe(&line);
+                }
+                ui.log().output(ansi::parse(&line), program, stream)
+            }
+            LineEvent::ReplaceNewest(stream, line) => {
+                ui.log().replace_output(ansi::parse(&line), program, stream)
+            }
+        }
+    }
+}
+
+// One of the child's output streams
+struct Output {
+    master: File,
+    splitter: LineSplitter,
+    open: bool,
+}
+
+impl Output {
+    fn new(master: File, stream: Stream) -> Self {
+        Output {
+            master,
+            splitter: LineSplitter::new(stream),
+            open: true,
+        }
+    }
+
+    // Reads whatever is available and closes the stream once the child closed its side
+    fn read(&mut self, buf: &mut [u8], events: &mut Vec<LineEvent>) {
+        match self.master.read(buf) {
+            Ok(0) => self.close(events),
+            Ok(n) => self.splitter.feed(&buf[..n], events),
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+            // EIO means that the child (and everything it spawned) closed the pty
+            Err(_) => self.close(events),
+        }
+    }
+
+    fn close(&mut self, events: &mut Vec<LineEvent>) {
+        self.splitter.finish(events);
+        self.open = false;
+    }
+}
+
+// The program a shell command runs, e.g. "make" for "ZSTD_CLEVEL=19 make modules_install"
+fn program_name(command: &str) -> Arc<str> {
+    command
+        .split_whitespace()
+        .find(|word| !word.contains('='))
+        .unwrap_or("sh")
+        .into()
+}
+
 fn run_process_impl(command: &str, user: User, ui: &mut Arc<Mutex<UI>>) -> Result<()> {
-    let mut child = match user {
-        User::Root => Command::new("/bin/sh")
-            .arg("-c")
-            .arg(format!("{} 2>&1", command))
-            .stdout(Stdio::piped())
-            .stderr(Stdio::piped())
-            .spawn()?,
-        User::User => {
-            // We need to switch user
-            Command::new("sudo")
-                .arg("-u")
-                .arg(env::var("SUDO_USER")?)
-                .arg("/bin/sh")
-                .arg("-c")
-                .arg(format!("{} 2>&1", command))
-                .stdout(Stdio::piped())
-                .stderr(Stdio::piped())
-                .spawn()?
+    ui.lock().unwrap().log().debug(format!("$ {}", command));
+    let program = program_name(command);
+    let start = Instant::now();
+
+    // Both streams get their own pty, so they can be told apart while still being terminals
+    let (stdout_master, stdout_slave) = open_pty()?;
+    let (stderr_master, stderr_slave) = open_pty()?;
+    let mut child = {
+        let mut cmd = match user {
+            User::Root => Command::new("/bin/sh"),
+            User::User => {
+                // We need to switch user
+                let mut cmd = Command::new("sudo");
+                cmd.arg("-u").arg(env::var("SUDO_USER")?).arg("/bin/sh");
+                cmd
+            }
+        };
+        cmd.arg("-c")
+            .arg(command)
+            .stdin(Stdio::from(stdout_slave.try_clone()?))
+            .stdout(Stdio::from(stdout_slave))
+            .stderr(Stdio::from(stderr_slave));
+        unsafe {
+            cmd.pre_exec(|| {
+                // Make the stdout pty the controlling terminal of the child
+                if libc::setsid() == -1 || libc::ioctl(0, libc::TIOCSCTTY, 0) == -1 {
+                    return Err(io::Error::last_os_error());
+                }
+                Ok(())
+            });
         }
+        // cmd holds the last handles to the slave side and drops them here, so reading from
+        // master fails with EIO once the child (and all of its children) exited.
+        cmd.spawn()?
     };
 
-    {
-        let mut line: String = String::new();
-        let mut buf = [0];
-        while child.stdout.as_mut().unwrap().read_exact(&mut buf).is_ok() {
-            if buf[0] as char == '\r' {
-                ui.lock().unwrap().log().replace_newest(line.clone());
-                line.clear();
+    let mut outputs = [
+        Output::new(stdout_master, Stream::Stdout),
+        Output::new(stderr_master, Stream::Stderr),
+    ];
+    let mut events = Vec::new();
+    let mut buf = [0; 4096];
+    while outputs.iter().any(|output| output.open) {
+        let mut fds = outputs.each_ref().map(|output| libc::pollfd {
+            // Negative fds are ignored by poll
+            fd: match output.open {
+                true => output.master.as_raw_fd(),
+                false => -1,
+            },
+            events: libc::POLLIN,
+            revents: 0,
+        });
+        if unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, -1) } == -1 {
+            let err = io::Error::last_os_error();
+            if err.kind() == io::ErrorKind::Interrupted {
                 continue;
             }
-            if buf[0] as char == '\n' {
-                ui.lock().unwrap().log().append(line.clone());
-                line.clear();
-                continue;
+            // Don't leave the child running without anyone reading its output
+            let _ = child.kill();
+            let _ = child.wait();
+            return Err(anyhow!("Failed to poll output of {}: {}", command, err));
+        }
+
+        for (output, fd) in outputs.iter_mut().zip(fds) {
+            if fd.revents != 0 {
+                output.read(&mut buf, &mut events);
             }
-            line.push(buf[0] as char);
         }
+        flush_events(&mut events, &program, ui);
     }
 
-    match child.wait()?.code() {
+    let status = child.wait()?;
+    record_status(&status, start, ui);
+    check_status(command, status)
+}
+
+fn record_status(status: &ExitStatus, start: Instant, ui: &Arc<Mutex<UI>>) {
+    ui.lock().unwrap().log().debug(format!(
+        "-> {} after {:.1}s",
+        match status.code() {
+            Some(code) => format!("exit code {}", code),
+            None => String::from("signaled"),
+        },
+        start.elapsed().as_secs_f32()
+    ));
+}
+
+fn check_status(command: &str, status: ExitStatus) -> Result<()> {
+    match status.code() {
         Some(code) => {
             if code == 0 {
                 Ok(())
@@ -67,6 +303,34 @@ fn run_process_impl(command: &str, user: User, ui: &mut Arc<Mutex<UI>>) -> Resul
     }
 }
 
+// Quotes s for use in /bin/sh commands
+pub fn shell_quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', "'\\''"))
+}
+
+// Runs a command for its stdout, which is returned instead of logged. stderr still ends up in the
+// log.
+pub fn capture_process(command: &str, ui: &mut Arc<Mutex<UI>>) -> Result<String> {
+    ui.lock().unwrap().log().debug(format!("$ {}", command));
+    let program = program_name(command);
+    let start = Instant::now();
+
+    let output = Command::new("/bin/sh")
+        .arg("-c")
+        .arg(command)
+        .stdin(Stdio::null())
+        .output()?;
+    {
+        let mut ui = ui.lock().unwrap();
+        for line in String::from_utf8_lossy(&output.stderr).lines() {
+            ui.log().output(ansi::parse(line), &program, Stream::Stderr);
+        }
+    }
+    record_status(&output.status, start, ui);
+    check_status(command, output.status)?;
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
 pub fn run_process(command: &str, ui: &mut Arc<Mutex<UI>>) -> Result<()> {
     run_process_impl(command, User::Root, ui)
 }
@@ -74,3 +338,83 @@ pub fn run_process(command: &str, ui: &mut Arc<Mutex<UI>>) -> Result<()> {
 pub fn run_process_user(command: &str, ui: &mut Arc<Mutex<UI>>) -> Result<()> {
     run_process_impl(command, User::User, ui)
 }
+
+// Runs an interactive program (e.g. menuconfig) on the real terminal. The TUI is suspended
+// while it runs.
+pub fn run_process_attached(command: &str, ui: &mut Arc<Mutex<UI>>) -> Result<()> {
+    ui.lock().unwrap().log().debug(format!("$ {}", command));
+    let start = Instant::now();
+
+    let future = ui.lock().unwrap().suspend();
+    future.wait();
+    let status = Command::new("/bin/sh").arg("-c").arg(command).status();
+    future.resume();
+
+    let status = status?;
+    record_status(&status, start, ui);
+    check_status(command, status)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn split(chunks: &[&[u8]]) -> Vec<LineEvent> {
+        let mut splitter = LineSplitter::new(Stream::Stdout);
+        let mut events = Vec::new();
+        for chunk in chunks {
+            splitter.feed(chunk, &mut events);
+        }
+        splitter.finish(&mut events);
+        events
+    }
+
+    fn append(line: &str) -> LineEvent {
+        LineEvent::Append(Stream::Stdout, String::from(line))
+    }
+
+    fn replace(line: &str) -> LineEvent {
+        LineEvent::ReplaceNewest(Stream::Stdout, String::from(line))
+    }
+
+    #[test]
+    fn splits_lines() {
+        assert_eq!(split(&[b"a\nb\n"]), vec![append("a"), append("b")]);
+        assert_eq!(
+            split(&[b"a\n\nb"]),
+            vec![append("a"), append(""), append("b")]
+        );
+    }
+
+    #[test]
+    fn crlf_is_a_newline() {
+        assert_eq!(split(&[b"a\r\nb\r\n"]), vec![append("a"), append("b")]);
+    }
+
+    #[test]
+    fn crlf_split_across_reads() {
+        assert_eq!(split(&[b"a\r", b"\nb\n"]), vec![append("a"), append("b")]);
+    }
+
+    #[test]
+    fn carriage_return_replaces_the_line() {
+        assert_eq!(
+            split(&[b"10%\r", b"50%\r100%\n"]),
+            vec![append("10%"), replace("50%"), replace("100%")]
+        );
+        // A trailing \r at EOF still ends the line
+        assert_eq!(split(&[b"10%\r"]), vec![append("10%")]);
+    }
+
+    #[test]
+    fn multibyte_split_across_reads() {
+        let text = "größe\n".as_bytes();
+        // Inside the two bytes of 'ö'
+        assert_eq!(split(&[&text[..3], &text[3..]]), vec![append("größe")]);
+    }
+
+    #[test]
+    fn invalid_utf8_is_replaced() {
+        assert_eq!(split(&[b"a\xffb\n"]), vec![append("a\u{fffd}b")]);
+    }
+}
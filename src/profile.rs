@@ -0,0 +1,403 @@
+use std::{env, fs, io::ErrorKind, thread};
+
+use anyhow::{anyhow, Result};
+
+use crate::{kconfig::Config, process::shell_quote};
+
+const DEFAULT_PATH: &str = "/etc/rkbuild/profile";
+// Outside of the tmpfs, so it survives between builds
+const DEFAULT_CCACHE_DIR: &str = "/var/cache/rkbuild/ccache";
+
+pub struct Ccache {
+    pub dir: String,
+    // e.g. "20G", as understood by ccache -M
+    pub max_size: Option<String>,
+}
+
+// Team wide build settings. "key = value" lines, "#" at the start of a line or after whitespace
+// starts a comment. Keys may appear multiple times (e.g. one "require" per symbol).
+#[derive(Default)]
+pub struct Profile {
+    pub path: String,
+    entries: Vec<(String, String)>,
+    // Set by validate()
+    jobs: String,
+    nice: Option<i32>,
+    ionice_class: Option<u8>,
+    memory_max: Option<String>,
+}
+
+impl Profile {
+    // A missing profile is the same as an empty one
+    pub fn load() -> Result<Self> {
+        let path = env::var("RKBUILD_PROFILE").unwrap_or(String::from(DEFAULT_PATH));
+        let content = match fs::read_to_string(&path) {
+            Ok(content) => content,
+            Err(err) if err.kind() == ErrorKind::NotFound => String::new(),
+            Err(err) => return Err(anyhow!("Failed to read {}: {}", path, err)),
+        };
+        Self::parse(path, &content)
+    }
+
+    fn parse(path: String, content: &str) -> Result<Self> {
+        let mut entries = Vec::new();
+        for (idx, line) in content.lines().enumerate() {
+            let line = strip_comment(line).trim();
+            if line.is_empty() {
+                continue;
+            }
+            let Some((key, value)) = line.split_once('=') else {
+                return Err(anyhow!("{}:{}: Expected \"key = value\"", path, idx + 1));
+            };
+            entries.push((String::from(key.trim()), String::from(value.trim())));
+        }
+        Ok(Profile {
+            path,
+            entries,
+            ..Default::default()
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    // All values of the key, in the order they were written
+    pub fn get_all(&self, key: &str) -> Vec<&str> {
+        self.entries
+            .iter()
+            .filter(|(entry_key, _)| entry_key == key)
+            .map(|(_, value)| value.as_str())
+            .collect()
+    }
+
+    // The last value wins
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.get_all(key).last().copied()
+    }
+
+    // "arch" and "cross_compile" for building for other machines.
+    // "toolchain = gcc" (default) or "toolchain = llvm", with "llvm_ias = 0/1" to choose the
+    // assembler
+    pub fn make_vars(&self) -> Result<Vec<String>> {
+        let mut vars = Vec::new();
+        if let Some(arch) = self.get("arch") {
+            vars.push(format!("ARCH={}", shell_quote(arch)));
+        }
+        let cross_compile = self.get("cross_compile").unwrap_or("");
+        if !cross_compile.is_empty() {
+            vars.push(format!("CROSS_COMPILE={}", shell_quote(cross_compile)));
+        }
+        let compiler = match self.get("toolchain").unwrap_or("gcc") {
+            "gcc" => {
+                if self.get("llvm_ias").is_some() {
+                    return Err(anyhow!("llvm_ias needs toolchain = llvm"));
+                }
+                format!("{}gcc", cross_compile)
+            }
+            "llvm" => {
+                vars.push(String::from("LLVM=1"));
+                match self.get("llvm_ias") {
+                    Some(ias @ ("0" | "1")) => vars.push(format!("LLVM_IAS={}", ias)),
+                    Some(ias) => return Err(anyhow!("Invalid llvm_ias: {}", ias)),
+                    None => {}
+                }
+                // clang picks the target from ARCH
+                String::from("clang")
+            }
+            toolchain => return Err(anyhow!("Unknown toolchain: {}", toolchain)),
+        };
+        if let Some(ccache) = self.ccache()? {
+            vars.push(format!("CC='ccache {}'", compiler));
+            vars.push(format!("CCACHE_DIR={}", shell_quote(&ccache.dir)));
+            // Every build extracts to the same place, but hash the paths relative to it anyway
+            vars.push(String::from("CCACHE_BASEDIR=/tmp/linux"));
+            vars.push(String::from("CCACHE_NOHASHDIR=1"));
+        }
+        Ok(vars)
+    }
+
+    // Parses the compile settings once, so a typo fails the build before the configuration
+    // instead of after it
+    pub fn validate(&mut self) -> Result<()> {
+        let mut jobs = match self.get("jobs") {
+            Some(jobs) => match jobs.parse::<usize>() {
+                Ok(count) if count > 0 => format!("-j{}", count),
+                _ => return Err(anyhow!("Invalid jobs: {}", jobs)),
+            },
+            None => format!(
+                "-j{}",
+                thread::available_parallelism().map_or(1, |count| count.get())
+            ),
+        };
+        if let Some(load) = self.get("load_average") {
+            if load.parse::<f64>().is_err() {
+                return Err(anyhow!("Invalid load_average: {}", load));
+            }
+            jobs.push_str(&format!(" -l{}", load));
+        }
+
+        let nice = match self.get("nice") {
+            Some(nice) => match nice.parse::<i32>() {
+                Ok(nice @ -20..=19) => Some(nice),
+                _ => return Err(anyhow!("Invalid nice: {}", nice)),
+            },
+            None => None,
+        };
+        let ionice_class = match self.get("ionice_class") {
+            Some("realtime" | "1") => Some(1),
+            Some("best-effort" | "2") => Some(2),
+            Some("idle" | "3") => Some(3),
+            Some(class) => return Err(anyhow!("Invalid ionice_class: {}", class)),
+            None => None,
+        };
+        let memory_max = match self.get("memory_max") {
+            Some(memory_max) if is_memory_size(memory_max) => Some(String::from(memory_max)),
+            Some(memory_max) => return Err(anyhow!("Invalid memory_max: {}", memory_max)),
+            None => None,
+        };
+
+        self.jobs = jobs;
+        self.nice = nice;
+        self.ionice_class = ionice_class;
+        self.memory_max = memory_max;
+        Ok(())
+    }
+
+    // "jobs" (default: number of CPUs) and "load_average" for make's -j and -l
+    pub fn jobs(&self) -> &str {
+        &self.jobs
+    }
+
+    // Runs the command with "nice", "ionice_class" and a "memory_max" cgroup limit, if set
+    pub fn limit(&self, command: &str) -> String {
+        if self.nice.is_none() && self.ionice_class.is_none() && self.memory_max.is_none() {
+            return String::from(command);
+        }
+        let mut wrapper = String::new();
+        if let Some(memory_max) = &self.memory_max {
+            // A scope keeps the build attached to our pty
+            wrapper.push_str(&format!(
+                "systemd-run --scope --quiet -p MemoryMax={} -- ",
+                memory_max
+            ));
+        }
+        if let Some(class) = self.ionice_class {
+            wrapper.push_str(&format!("ionice -c {} ", class));
+        }
+        let command = match self.nice {
+            // "nice -n" would be relative to our own niceness, which main already raised
+            Some(nice) => format!("renice -n {} -p $$ >/dev/null; {}", nice, command),
+            None => String::from(command),
+        };
+        format!("{}/bin/sh -c {}", wrapper, shell_quote(&command))
+    }
+
+    // Builds are exported there instead of being installed on this machine
+    pub fn output_dir(&self) -> Option<&str> {
+        self.get("output_dir")
+    }
+
+    // "ccache = on" wraps the compiler, "ccache_dir" and "ccache_max_size" configure the cache
+    pub fn ccache(&self) -> Result<Option<Ccache>> {
+        match self.get("ccache").unwrap_or("off") {
+            "off" => Ok(None),
+            "on" => Ok(Some(Ccache {
+                dir: String::from(self.get("ccache_dir").unwrap_or(DEFAULT_CCACHE_DIR)),
+                max_size: self.get("ccache_max_size").map(String::from),
+            })),
+            ccache => Err(anyhow!("Invalid ccache: {}", ccache)),
+        }
+    }
+
+    // "lto = thin" or "lto = none" (default). Only works with clang
+    pub fn lto(&self) -> Result<bool> {
+        match self.get("lto").unwrap_or("none") {
+            "none" => Ok(false),
+            "thin" if self.get("toolchain") == Some("llvm") => Ok(true),
+            "thin" => Err(anyhow!("lto = thin needs toolchain = llvm")),
+            lto => Err(anyhow!("Unknown lto: {}", lto)),
+        }
+    }
+
+    // "require = CONFIG_FOO=y" needs exactly that value, "require = CONFIG_FOO" y or m.
+    // "forbid = CONFIG_FOO=m" rejects that value, "forbid = CONFIG_FOO" y and m.
+    // Returns one message per violated rule.
+    pub fn check_policy(&self, config: &Config) -> Vec<String> {
+        let value = |symbol: &str| {
+            config
+                .get(symbol.strip_prefix("CONFIG_").unwrap_or(symbol))
+                .map(String::as_str)
+                .unwrap_or("n")
+        };
+        let enabled = |value: &str| value == "y" || value == "m";
+
+        let mut violations = Vec::new();
+        for rule in self.get_all("require") {
+            match rule.split_once('=') {
+                Some((symbol, required)) if value(symbol) != required => violations.push(format!(
+                    "{} is {}, but {} is required",
+                    symbol,
+                    value(symbol),
+                    required
+                )),
+                None if !enabled(value(rule)) => {
+                    violations.push(format!("{} is not enabled, but required", rule))
+                }
+                _ => {}
+            }
+        }
+        for rule in self.get_all("forbid") {
+            match rule.split_once('=') {
+                Some((symbol, forbidden)) if value(symbol) == forbidden => {
+                    violations.push(format!("{} is {}, which is forbidden", symbol, forbidden))
+                }
+                None if enabled(value(rule)) => {
+                    violations.push(format!("{} is {}, but forbidden", rule, value(rule)))
+                }
+                _ => {}
+            }
+        }
+        violations
+    }
+}
+
+// Bytes with an optional K/M/G/T suffix, a percentage of the RAM or "infinity", like systemd takes
+fn is_memory_size(size: &str) -> bool {
+    let number = size.strip_suffix(['K', 'M', 'G', 'T', '%']).unwrap_or(size);
+    size == "infinity" || (!number.is_empty() && number.chars().all(|c| c.is_ascii_digit()))
+}
+
+// "#" inside a value (e.g. "ccache_dir = /cache#1") is kept
+fn strip_comment(line: &str) -> &str {
+    let mut prev = ' ';
+    for (idx, c) in line.char_indices() {
+        if c == '#' && prev.is_whitespace() {
+            return &line[..idx];
+        }
+        prev = c;
+    }
+    line
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kconfig;
+
+    fn profile(content: &str) -> Profile {
+        Profile::parse(String::from("profile"), content).unwrap()
+    }
+
+    fn violations(profile_content: &str, config: &str) -> Vec<String> {
+        profile(profile_content).check_policy(&kconfig::parse(config))
+    }
+
+    #[test]
+    fn comments() {
+        let profile = profile("# whole line\njobs = 4 # trailing\nccache_dir = /cache#1\n\n");
+        assert_eq!(profile.get("jobs"), Some("4"));
+        assert_eq!(profile.get("ccache_dir"), Some("/cache#1"));
+        assert_eq!(profile.entries.len(), 2);
+    }
+
+    #[test]
+    fn missing_equals_is_an_error() {
+        assert!(Profile::parse(String::from("profile"), "jobs 4").is_err());
+    }
+
+    #[test]
+    fn repeated_keys() {
+        let profile = profile("require = CONFIG_A\nrequire = CONFIG_B\njobs = 2\njobs = 3");
+        assert_eq!(profile.get_all("require"), vec!["CONFIG_A", "CONFIG_B"]);
+        assert_eq!(profile.get("jobs"), Some("3"));
+    }
+
+    #[test]
+    fn validate() {
+        let mut valid = profile("jobs = 8\nload_average = 12.5");
+        valid.validate().unwrap();
+        assert_eq!(valid.jobs(), "-j8 -l12.5");
+
+        for invalid in [
+            "jobs = 0",
+            "load_average = high",
+            "nice = 20",
+            "ionice_class = low",
+            "memory_max = 16GB",
+        ] {
+            assert!(profile(invalid).validate().is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn limit() {
+        let mut unlimited = profile("");
+        unlimited.validate().unwrap();
+        assert!(unlimited.jobs().starts_with("-j"));
+        assert_eq!(unlimited.limit("make"), "make");
+
+        let mut limited = profile("nice = 5\nionice_class = idle\nmemory_max = 16G");
+        limited.validate().unwrap();
+        assert_eq!(
+            limited.limit("make"),
+            "systemd-run --scope --quiet -p MemoryMax=16G -- ionice -c 3 /bin/sh -c \
+             'renice -n 5 -p $$ >/dev/null; make'"
+        );
+    }
+
+    #[test]
+    fn require_without_value() {
+        let rule = "require = CONFIG_A";
+        assert!(violations(rule, "CONFIG_A=y").is_empty());
+        assert!(violations(rule, "CONFIG_A=m").is_empty());
+        assert_eq!(
+            violations(rule, "# CONFIG_A is not set"),
+            vec!["CONFIG_A is not enabled, but required"]
+        );
+        assert_eq!(violations(rule, "").len(), 1);
+    }
+
+    #[test]
+    fn require_with_value() {
+        let rule = "require = CONFIG_A=y";
+        assert!(violations(rule, "CONFIG_A=y").is_empty());
+        assert_eq!(
+            violations(rule, "CONFIG_A=m"),
+            vec!["CONFIG_A is m, but y is required"]
+        );
+        assert_eq!(
+            violations("require = CONFIG_A=n", "CONFIG_A=y"),
+            vec!["CONFIG_A is y, but n is required"]
+        );
+        assert!(violations("require = CONFIG_A=n", "").is_empty());
+    }
+
+    #[test]
+    fn forbid_without_value() {
+        let rule = "forbid = CONFIG_A";
+        assert!(violations(rule, "").is_empty());
+        assert!(violations(rule, "# CONFIG_A is not set").is_empty());
+        assert_eq!(
+            violations(rule, "CONFIG_A=m"),
+            vec!["CONFIG_A is m, but forbidden"]
+        );
+        assert_eq!(violations(rule, "CONFIG_A=y").len(), 1);
+    }
+
+    #[test]
+    fn forbid_with_value() {
+        let rule = "forbid = CONFIG_A=m";
+        assert!(violations(rule, "CONFIG_A=y").is_empty());
+        assert_eq!(
+            violations(rule, "CONFIG_A=m"),
+            vec!["CONFIG_A is m, which is forbidden"]
+        );
+    }
+
+    #[test]
+    fn reports_every_violation() {
+        let rules = "require = CONFIG_A\nforbid = CONFIG_B\nrequire = CONFIG_C=y";
+        assert_eq!(violations(rules, "CONFIG_B=y\nCONFIG_C=y").len(), 2);
+    }
+}
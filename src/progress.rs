@@ -0,0 +1,152 @@
+use std::{fs, path::Path, time::Instant};
+
+use ratatui::{
+    prelude::Rect,
+    style::{Color, Style},
+    widgets::{Block, Borders, Gauge},
+    Frame,
+};
+
+use crate::{
+    kconfig::{self, Config},
+    tui,
+};
+
+// Tracks the compile by counting the objects kbuild reports
+pub struct Progress {
+    expected: usize,
+    // The expected count is a guess from the Makefiles, not from a previous build
+    estimated: bool,
+    compiled: usize,
+    start: Instant,
+}
+
+impl Progress {
+    pub fn new(expected: usize, estimated: bool) -> Self {
+        Progress {
+            expected: expected.max(1),
+            estimated,
+            compiled: 0,
+            start: Instant::now(),
+        }
+    }
+
+    pub fn compiled(&self) -> usize {
+        self.compiled
+    }
+
+    // Looks for kbuild's quiet output, e.g. "  CC [M]  drivers/foo/bar.o"
+    pub fn observe(&mut self, line: &str) {
+        let mut words = line.split_whitespace();
+        if !matches!(words.next(), Some("CC") | Some("AS")) {
+            return;
+        }
+        if words.last().map(|target| target.ends_with(".o")) == Some(true) {
+            self.compiled += 1;
+        }
+    }
+
+    pub fn draw(&self, frame: &mut Frame<tui::Backend>, area: Rect) {
+        let ratio = (self.compiled as f64 / self.expected as f64).min(1.0);
+        let eta = match self.compiled {
+            0 => String::from("--:--"),
+            compiled => {
+                let elapsed = self.start.elapsed().as_secs_f64();
+                let remaining = self.expected.saturating_sub(compiled) as f64;
+                let secs = (elapsed / compiled as f64 * remaining) as u64;
+                format!("{:02}:{:02}", secs / 60, secs % 60)
+            }
+        };
+        let label = format!(
+            "{}/{}{} objects, ETA {}",
+            self.compiled,
+            match self.estimated {
+                true => "~",
+                false => "",
+            },
+            self.expected,
+            eta
+        );
+        let gauge = Gauge::default()
+            .block(Block::default().borders(Borders::ALL).title("Compiling"))
+            .gauge_style(Style::default().fg(Color::LightGreen))
+            .ratio(ratio)
+            .label(label);
+        frame.render_widget(gauge, area);
+    }
+}
+
+// FNV-1a, stable across Rust versions, unlike DefaultHasher
+pub fn config_hash(config: &[u8]) -> String {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in config {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    format!("{:016x}", hash)
+}
+
+// Directories that don't end up in the kernel
+const SKIPPED_DIRS: [&str; 4] = ["Documentation", "samples", "scripts", "tools"];
+
+// Rough guess of the number of objects, by counting the .o files in enabled Makefile lines
+pub fn estimate_objects(source_dir: &Path, arch: &str, config: &Config) -> usize {
+    let mut count = 0;
+    let mut dirs = vec![source_dir.to_path_buf()];
+    while let Some(dir) = dirs.pop() {
+        let Ok(entries) = fs::read_dir(&dir) else {
+            continue;
+        };
+        for entry in entries.filter_map(|entry| entry.ok()) {
+            let path = entry.path();
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if path.is_dir() {
+                let relative = path.strip_prefix(source_dir).unwrap_or(&path);
+                let skipped = SKIPPED_DIRS.contains(&name.as_str())
+                    || (relative.parent() == Some(Path::new("arch")) && name != arch);
+                if !skipped && !path.is_symlink() {
+                    dirs.push(path);
+                }
+            } else if name == "Makefile" || name == "Kbuild" {
+                if let Ok(makefile) = fs::read_to_string(&path) {
+                    count += count_makefile_objects(&makefile, config);
+                }
+            }
+        }
+    }
+    count
+}
+
+// Counts lines like "obj-$(CONFIG_FOO) += foo.o bar.o" or "foo-y := a.o b.o"
+fn count_makefile_objects(makefile: &str, config: &Config) -> usize {
+    let makefile = makefile.replace("\\\n", " ");
+    let mut count = 0;
+    for line in makefile.lines() {
+        let Some((target, objects)) = line
+            .split_once("+=")
+            .or_else(|| line.split_once(":="))
+            .or_else(|| line.split_once('='))
+        else {
+            continue;
+        };
+        let target = target.trim();
+        let enabled =
+            if target.ends_with("-y") || target.ends_with("-m") || target.ends_with("-objs") {
+                true
+            } else if let Some(symbol) = target
+                .split_once("-$(CONFIG_")
+                .and_then(|(_, symbol)| symbol.strip_suffix(')'))
+            {
+                kconfig::is_enabled(config, symbol)
+            } else {
+                false
+            };
+        if enabled {
+            count += objects
+                .split_whitespace()
+                .filter(|object| object.ends_with(".o"))
+                .count();
+        }
+    }
+    count
+}
@@ -0,0 +1,68 @@
+use std::fs;
+
+use anyhow::Result;
+
+// Everything rkbuild remembers between runs
+const STATE_DIR: &str = "/var/lib/rkbuild";
+
+// State files consist of "key=value" lines. A key may appear multiple times.
+fn read(name: &str) -> Vec<(String, String)> {
+    let Ok(content) = fs::read_to_string(format!("{}/{}", STATE_DIR, name)) else {
+        return Vec::new();
+    };
+    content
+        .lines()
+        .filter_map(|line| line.split_once('='))
+        .map(|(key, value)| (String::from(key), String::from(value)))
+        .collect()
+}
+
+// All values stored for the key, in the order they were written
+pub fn get_all(name: &str, key: &str) -> Vec<String> {
+    read(name)
+        .into_iter()
+        .filter(|(entry_key, _)| entry_key == key)
+        .map(|(_, value)| value)
+        .collect()
+}
+
+pub fn get(name: &str, key: &str) -> Option<String> {
+    get_all(name, key).into_iter().next()
+}
+
+// Replaces all values of the key
+pub fn set_all(name: &str, key: &str, values: &[String]) -> Result<()> {
+    let mut entries: Vec<(String, String)> = read(name)
+        .into_iter()
+        .filter(|(entry_key, _)| entry_key != key)
+        .collect();
+    entries.extend(
+        values
+            .iter()
+            .map(|value| (String::from(key), value.replace('\n', " "))),
+    );
+
+    fs::create_dir_all(STATE_DIR)?;
+    let content: String = entries
+        .iter()
+        .map(|(key, value)| format!("{}={}\n", key, value))
+        .collect();
+    fs::write(format!("{}/{}", STATE_DIR, name), content)?;
+    Ok(())
+}
+
+pub fn set(name: &str, key: &str, value: &str) -> Result<()> {
+    set_all(name, key, &[String::from(value)])
+}
+
+// Appends the value to the key, moving it to the end if it's already there and dropping the
+// oldest values beyond max
+pub fn push(name: &str, key: &str, value: &str, max: usize) -> Result<()> {
+    let mut values: Vec<String> = get_all(name, key)
+        .into_iter()
+        .filter(|existing| existing != value)
+        .collect();
+    values.push(String::from(value));
+    let excess = values.len().saturating_sub(max);
+    set_all(name, key, &values[excess..])
+}
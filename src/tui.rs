@@ -2,6 +2,7 @@ use std::io::{self, Stdout};
 
 use anyhow::{Context, Result};
 use crossterm::{
+    event::{DisableBracketedPaste, EnableBracketedPaste},
     execute,
     terminal::{disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen},
 };
@@ -21,10 +22,36 @@ impl Tui {
         enable_raw_mode().context("Failed to enable raw mode!")?;
         execute!(terminal.backend_mut(), EnterAlternateScreen)
             .context("Failed to enable alternate screen!")?;
+        execute!(terminal.backend_mut(), EnableBracketedPaste)
+            .context("Failed to enable bracketed paste!")?;
         Ok(Self { terminal })
     }
 
-    pub fn draw<Fun>(&mut self, fun: Fun) -> Result<CompletedFrame, std::io::Error>
+    // Hands the terminal back, e.g. for running menuconfig
+    pub fn suspend(&mut self) -> Result<()> {
+        disable_raw_mode().context("Failed to disable raw mode!")?;
+        execute!(self.terminal.backend_mut(), DisableBracketedPaste)
+            .context("Failed to disable bracketed paste!")?;
+        execute!(self.terminal.backend_mut(), LeaveAlternateScreen)
+            .context("Failed to disable alternate screen!")?;
+        self.terminal
+            .show_cursor()
+            .context("Unable to show cursor!")?;
+        Ok(())
+    }
+
+    pub fn resume(&mut self) -> Result<()> {
+        enable_raw_mode().context("Failed to enable raw mode!")?;
+        execute!(self.terminal.backend_mut(), EnterAlternateScreen)
+            .context("Failed to enable alternate screen!")?;
+        execute!(self.terminal.backend_mut(), EnableBracketedPaste)
+            .context("Failed to enable bracketed paste!")?;
+        // Whatever ran in between left garbage in ratatuis buffers, so redraw everything
+        self.terminal.clear().context("Failed to clear terminal!")?;
+        Ok(())
+    }
+
+    pub fn draw<Fun>(&mut self, fun: Fun) -> Result<CompletedFrame<'_>, std::io::Error>
     where
         Fun: FnOnce(&mut Frame<Backend>),
     {
@@ -37,6 +64,9 @@ impl Drop for Tui {
         disable_raw_mode()
             .context("Failed to disabled raw mode!")
             .unwrap();
+        execute!(self.terminal.backend_mut(), DisableBracketedPaste)
+            .context("Failed to disable bracketed paste!")
+            .unwrap();
         execute!(self.terminal.backend_mut(), LeaveAlternateScreen)
             .context("Failed to disable alternate screen!")
             .unwrap();
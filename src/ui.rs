@@ -1,42 +1,91 @@
 use std::{
+    sync::mpsc::{channel, Receiver, Sender},
     time::Duration,
 };
 
 use crate::{
     log::Log,
+    pipeline::Pipeline,
+    progress::Progress,
     tui::{self},
     user::UserInput,
 };
-use anyhow::{Result};
-use crossterm::event::{self, Event, KeyCode, KeyEvent};
+use anyhow::Result;
+use crossterm::event::{self, Event, KeyCode, KeyEvent, KeyModifiers};
 use ratatui::{
     prelude::{Constraint, Direction, Layout},
+    widgets::Clear,
     Frame,
 };
 
 pub enum TickResult {
     Ok,
     Exit,
+    Suspend(SuspendRequest),
 }
 
 pub struct UI<'a> {
     log: Log<'a>,
     input: UserInput<'a>,
+    pipeline: Pipeline,
+    // Only while compiling
+    progress: Option<Progress>,
+    suspend_request: Option<SuspendRequest>,
+}
+
+// Handed to the main thread, which gives up the terminal until the requester is done with it
+pub struct SuspendRequest {
+    suspended: Sender<()>,
+    resume: Receiver<()>,
+}
+
+// Held by the thread that wants to use the terminal directly
+pub struct SuspendFuture {
+    suspended: Receiver<()>,
+    resume: Sender<()>,
+}
+
+impl SuspendRequest {
+    // Blocks until the terminal is given back
+    pub fn hand_over(self) {
+        self.suspended.send(()).expect("Failed to send!");
+        self.resume.recv().expect("Failed to receive");
+    }
+}
+
+impl SuspendFuture {
+    // Blocks until the TUI released the terminal
+    pub fn wait(&self) {
+        self.suspended.recv().expect("Failed to receive");
+    }
+
+    pub fn resume(self) {
+        self.resume.send(()).expect("Failed to send!");
+    }
 }
 
 pub struct UIController<'a> {
     pub ui: UI<'a>,
 }
 
+impl<'a> Default for UI<'a> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl<'a> UI<'a> {
     pub fn new() -> Self {
         Self {
             input: UserInput::new(),
             log: Log::new(),
+            pipeline: Pipeline::new(),
+            progress: None,
+            suspend_request: None,
         }
     }
 
-    pub fn log<'b>(&'b mut self) -> &mut Log<'a> {
+    pub fn log(&mut self) -> &mut Log<'a> {
         &mut self.log
     }
 
@@ -44,24 +93,61 @@ impl<'a> UI<'a> {
         &mut self.input
     }
 
+    pub fn pipeline(&mut self) -> &mut Pipeline {
+        &mut self.pipeline
+    }
+
+    pub fn progress(&mut self) -> &mut Option<Progress> {
+        &mut self.progress
+    }
+
+    // Asks the main thread to leave the TUI, so a program can use the terminal
+    pub fn suspend(&mut self) -> SuspendFuture {
+        let (suspended_sender, suspended_rec) = channel();
+        let (resume_sender, resume_rec) = channel();
+        self.suspend_request = Some(SuspendRequest {
+            suspended: suspended_sender,
+            resume: resume_rec,
+        });
+        SuspendFuture {
+            suspended: suspended_rec,
+            resume: resume_sender,
+        }
+    }
+
     pub fn tick(&mut self) -> Result<TickResult> {
+        // Don't eat the keys meant for the program we suspend for
+        if let Some(request) = self.suspend_request.take() {
+            return Ok(TickResult::Suspend(request));
+        }
+
         let mut event_queue: Vec<KeyEvent> = vec![];
 
-        loop {
-            match event::poll(Duration::from_millis(0))? {
-                true => match event::read()? {
-                    Event::Key(key) => {
-                        if key.code == KeyCode::Esc {
-                            return Ok(TickResult::Exit);
-                        } else {
-                            event_queue.push(key);
-                            continue;
-                        }
-                    }
-                    _ => continue,
-                },
-                false => break,
+        while event::poll(Duration::from_millis(0))? {
+            let keys = match event::read()? {
+                Event::Key(key) => vec![key],
+                // Pasted text is typed in char by char, newlines would confirm prompts
+                Event::Paste(text) => text
+                    .chars()
+                    .filter(|c| !c.is_control())
+                    .map(|c| KeyEvent::new(KeyCode::Char(c), KeyModifiers::NONE))
+                    .collect(),
+                _ => vec![],
             };
+            for key in keys {
+                // A wide prompt covers the log and pages through its own content
+                let paging = matches!(key.code, KeyCode::PageUp | KeyCode::PageDown);
+                // The log may use Esc to cancel a search
+                if !(paging && self.input.is_wide())
+                    && self.log.handle_key(&key, self.input.is_active())
+                {
+                    continue;
+                }
+                if key.code == KeyCode::Esc {
+                    return Ok(TickResult::Exit);
+                }
+                event_queue.push(key);
+            }
         }
         self.input.tick(&event_queue);
 
@@ -78,7 +164,28 @@ impl<'a> UI<'a> {
             ])
             .split(frame.size());
 
-        self.input.draw(frame, chunks[0]);
-        self.log.draw(frame, chunks[1]);
+        // One row per stage plus borders
+        let input_chunks = Layout::default()
+            .direction(Direction::Vertical)
+            .constraints([Constraint::Min(3), Constraint::Length(10)])
+            .split(chunks[0]);
+        self.pipeline.draw(frame, input_chunks[1]);
+        match &self.progress {
+            Some(progress) => {
+                let log_chunks = Layout::default()
+                    .direction(Direction::Vertical)
+                    .constraints([Constraint::Min(3), Constraint::Length(3)])
+                    .split(chunks[1]);
+                self.log.draw(frame, log_chunks[0]);
+                progress.draw(frame, log_chunks[1]);
+            }
+            None => self.log.draw(frame, chunks[1]),
+        }
+        if self.input.is_wide() {
+            frame.render_widget(Clear, chunks[1]);
+            self.input.draw(frame, chunks[1]);
+        } else {
+            self.input.draw(frame, input_chunks[0]);
+        }
     }
 }
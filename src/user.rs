@@ -1,8 +1,6 @@
-use std::{
-    sync::mpsc::{channel, Receiver, Sender},
-};
+use std::sync::mpsc::{channel, Receiver, Sender};
 
-use crate::{tui};
+use crate::tui;
 use crossterm::event::KeyEvent;
 use ratatui::{prelude::Rect, Frame};
 
@@ -11,13 +9,17 @@ pub enum ChoiceResult {
     Remove,
 }
 
+pub type RenderFunc<'a> = Box<
+    dyn FnMut(&mut Frame<tui::Backend>, Rect, &Option<Vec<KeyEvent>>) -> ChoiceResult
+        + 'a
+        + Send
+        + Sync,
+>;
+
 pub struct Choice<'a> {
-    pub render_func: Box<
-        dyn FnMut(&mut Frame<tui::Backend>, Rect, &Option<Vec<KeyEvent>>) -> ChoiceResult
-            + 'a
-            + Send
-            + Sync,
-    >,
+    pub render_func: RenderFunc<'a>,
+    // Drawn over the log instead of the narrow input column
+    pub wide: bool,
 }
 
 pub struct UserInputFuture {
@@ -40,8 +42,14 @@ impl<'a> Choice<'a> {
     {
         Choice {
             render_func: Box::new(render_func),
+            wide: false,
         }
     }
+
+    pub fn wide(mut self) -> Self {
+        self.wide = true;
+        self
+    }
 }
 
 impl UserInputFuture {
@@ -50,6 +58,12 @@ impl UserInputFuture {
     }
 }
 
+impl<'a> Default for UserInput<'a> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl<'a> UserInput<'a> {
     pub fn new() -> Self {
         UserInput {
@@ -77,8 +91,16 @@ impl<'a> UserInput<'a> {
         }
     }
 
-    pub fn tick(&mut self, event_queue: &Vec<KeyEvent>) {
-        self.event_queue = Some(event_queue.clone());
+    pub fn is_active(&self) -> bool {
+        self.current_choice.is_some()
+    }
+
+    pub fn is_wide(&self) -> bool {
+        matches!(&self.current_choice, Some(choice) if choice.wide)
+    }
+
+    pub fn tick(&mut self, event_queue: &[KeyEvent]) {
+        self.event_queue = Some(event_queue.to_vec());
     }
 
     pub fn draw(&mut self, frame: &mut Frame<tui::Backend>, draw_area: Rect) {